@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+// A temporary file next to its final destination. The content only becomes visible at the
+// destination after [StagedFile::commit], partial writes never replace an existing secret.
+//
+// WARN; The temporary file is removed when the object is dropped without being committed.
+pub struct StagedFile {
+    temp_path: PathBuf,
+    destination: PathBuf,
+    pub file: tokio::fs::File,
+    committed: bool,
+}
+
+impl StagedFile {
+    pub async fn create(destination: &Path) -> std::io::Result<Self> {
+        let parent = destination.parent().unwrap_or(Path::new("."));
+        let file_name = destination
+            .file_name()
+            .ok_or_else(|| std::io::Error::other("destination has no file name"))?;
+
+        let mut suffix = [0u8; 8];
+        getrandom::getrandom(&mut suffix)?;
+        let suffix: String = suffix.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".bss-{}", suffix));
+        let temp_path = parent.join(temp_name);
+
+        // Only the owner can access the content until the final permissions are applied
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .await?;
+
+        Ok(StagedFile {
+            temp_path,
+            destination: destination.to_owned(),
+            file,
+            committed: false,
+        })
+    }
+
+    // Applies ownership and permissions, flushes the content to disk and atomically moves the
+    // file to its destination. The rename is flushed too, so the destination survives a crash.
+    pub async fn commit(mut self, ownership: &super::ownership::Ownership) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::AsyncWriteExt;
+
+        self.file.flush().await?;
+        std::os::unix::fs::fchown(&self.file, Some(ownership.uid), Some(ownership.gid))?;
+        self.file
+            .set_permissions(std::fs::Permissions::from_mode(ownership.mode))
+            .await?;
+        self.file.sync_all().await?;
+        tokio::fs::rename(&self.temp_path, &self.destination).await?;
+        self.committed = true;
+
+        let parent = match self.destination.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        tokio::fs::File::open(parent).await?.sync_all().await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
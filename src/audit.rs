@@ -0,0 +1,534 @@
+// An append-only record of transfers, kept by the sender and by the process of the receiver that
+// delivers. Every entry holds the hash of the entry before it, so changing, removing or reordering
+// entries breaks the chain, see [verify].
+//
+// The log is a file of JSON lines. eg;
+// {"sequence":0,"previous":"0000..","time":"2024-05-01T12:00:00.000Z","side":"receive","peer":"vsock:cid=2,port=1024","secret":"db","digest":"9f86..","size":4,"destination":"/etc/db.conf","outcome":"delivered","hash":"2c26.."}
+//
+// WARN; The chain is not keyed, whoever can write the log can rewrite it with a valid chain,
+// eg; after removing entries from the end or anywhere else. The chain is only evidence against an
+// anchor kept elsewhere: keep the last hash reported by `bss audit verify` outside the reach of
+// the host, and check the log still contains it!
+use sha2::Digest;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// The previous hash of the first entry.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Bytes read at once while searching the last entry from the end of the log.
+const HEAD_CHUNK: u64 = 4096;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Send,
+    Receive,
+}
+
+// What an entry records about a single upload.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Transfer {
+    pub peer: String,
+    pub secret: String,
+    // SHA-256 of the plaintext content, unset when it wasn't transferred.
+    //
+    // WARN; The digest of a guessable secret reveals it, protect the log like the secrets!
+    pub digest: Option<String>,
+    pub size: u64,
+    // See [super::sink::Sink::describe_destination].
+    pub destination: String,
+    // "delivered" or the reason of the failure.
+    pub outcome: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Entry {
+    sequence: u64,
+    previous: String,
+    time: String,
+    side: Side,
+    #[serde(flatten)]
+    transfer: Transfer,
+}
+
+// A line of the log, the hash covers the serialized entry.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Line {
+    #[serde(flatten)]
+    entry: Entry,
+    hash: String,
+}
+
+fn hash(entry: &Entry) -> Result<String, serde_json::Error> {
+    Ok(hex::encode(sha2::Sha256::digest(serde_json::to_vec(
+        entry,
+    )?)))
+}
+
+// Digest and size of transferred content.
+#[derive(Default, Clone)]
+pub struct Content {
+    hasher: sha2::Sha256,
+    size: u64,
+}
+
+impl Content {
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // The digest of everything passed so far.
+    pub fn digest(&self) -> String {
+        hex::encode(self.hasher.clone().finalize())
+    }
+}
+
+// Passes a body through while adding it to the content.
+pub struct Digesting<'a, R> {
+    inner: R,
+    content: &'a mut Content,
+    // Bytes at the start of the buffer of the inner reader that are added already.
+    hashed: usize,
+}
+
+impl<'a, R> Digesting<'a, R> {
+    pub fn new(inner: R, content: &'a mut Content) -> Self {
+        Digesting {
+            inner,
+            content,
+            hashed: 0,
+        }
+    }
+}
+
+impl<R: tokio::io::AsyncBufRead + Unpin> tokio::io::AsyncRead for Digesting<'_, R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        use tokio::io::AsyncBufRead;
+
+        let available = std::task::ready!(self.as_mut().poll_fill_buf(cx))?;
+        let amount = available.len().min(buf.remaining());
+        buf.put_slice(&available[..amount]);
+        self.consume(amount);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: tokio::io::AsyncBufRead + Unpin> tokio::io::AsyncBufRead for Digesting<'_, R> {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let available = std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+        // NOTE; The buffer keeps returning the bytes that aren't consumed, they're added once
+        if available.len() > this.hashed {
+            this.content.update(&available[this.hashed..]);
+            this.hashed = available.len();
+        }
+        std::task::Poll::Ready(Ok(available))
+    }
+
+    fn consume(self: std::pin::Pin<&mut Self>, amount: usize) {
+        let this = self.get_mut();
+        std::pin::Pin::new(&mut this.inner).consume(amount);
+        this.hashed = this.hashed.saturating_sub(amount);
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    side: Side,
+    // NOTE; Locks of the file are per open file, the mutex serializes appends of this process
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    // Opens the log for appending, creating it when missing. The receiver opens it before
+    // confining writes, appending keeps working after.
+    pub fn open(path: &Path, side: Side) -> Result<Self, Box<dyn std::error::Error>> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| format!("failed to open audit log '{}': {}", path.display(), e))?;
+        // Fail early instead of failing to append after every transfer
+        {
+            let _lock = FileLock::exclusive(&file)?;
+            head(&file).map_err(|e| {
+                format!(
+                    "refusing to append to audit log '{}': {}",
+                    path.display(),
+                    e
+                )
+            })?;
+        }
+
+        Ok(AuditLog {
+            path: path.to_owned(),
+            side,
+            file: Mutex::new(file),
+        })
+    }
+
+    // Appends an entry for the transfer. Failing to append is reported but doesn't fail the
+    // transfer, which happened already.
+    pub fn record(&self, transfer: Transfer) {
+        let secret = transfer.secret.clone();
+        if let Err(e) = self.append(transfer) {
+            log::error!(
+                path:? = self.path, secret = secret.as_str(), error:% = e;
+                "Failed appending to the audit log"
+            );
+        }
+    }
+
+    // NOTE; Entries are small and written once per transfer, blocking the runtime is fine
+    fn append(&self, transfer: Transfer) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let file = self.file.lock().expect("audit log lock poisoned");
+        // Other processes may append to the same log
+        let _lock = FileLock::exclusive(&file)?;
+        let (sequence, previous) = match head(&file)? {
+            Some((sequence, hash)) => (sequence + 1, hash),
+            None => (0, GENESIS.to_string()),
+        };
+
+        let entry = Entry {
+            sequence,
+            previous,
+            time: super::logging::timestamp(),
+            side: self.side,
+            transfer,
+        };
+        let hash = hash(&entry)?;
+        let mut line = serde_json::to_vec(&Line { entry, hash })?;
+        line.push(b'\n');
+        // NOTE; A single write, appends of other processes don't interleave
+        (&*file).write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+// Holds an exclusive lock on the file until dropped.
+struct FileLock<'a>(&'a File);
+
+impl<'a> FileLock<'a> {
+    fn exclusive(file: &'a File) -> std::io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+// The sequence number and hash of the last entry, none for an empty log.
+fn head(file: &File) -> std::io::Result<Option<(u64, String)>> {
+    use std::os::unix::fs::FileExt;
+
+    let mut end = file.metadata()?.len();
+    if end == 0 {
+        return Ok(None);
+    }
+
+    // Reads backwards until the line before the last entry ends
+    let mut tail = Vec::new();
+    let line = loop {
+        let start = end.saturating_sub(HEAD_CHUNK);
+        let mut chunk = vec![0u8; (end - start) as usize];
+        file.read_exact_at(&mut chunk, start)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        end = start;
+
+        let Some(last) = tail.strip_suffix(b"\n") else {
+            return Err(invalid_log("the last entry is incomplete"));
+        };
+        match last.iter().rposition(|byte| *byte == b'\n') {
+            Some(position) => break &last[position + 1..],
+            None if start == 0 => break last,
+            None => continue,
+        }
+    };
+
+    let line: Line = serde_json::from_slice(line)
+        .map_err(|e| invalid_log(format!("the last entry is invalid: {}", e)))?;
+    Ok(Some((line.entry.sequence, line.hash)))
+}
+
+fn invalid_log(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+// Parses the arguments of the audit command.
+pub fn audit_main(mut parser: lexopt::Parser) -> Result<(), Box<dyn std::error::Error>> {
+    use lexopt::prelude::*;
+
+    let mut action = None;
+    let mut path = None;
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Value(value) if action.is_none() => {
+                action = Some(value.string()?);
+            }
+            Value(value) if path.is_none() => {
+                path = Some(std::path::PathBuf::from(value));
+            }
+            _ => return Err(arg.unexpected())?,
+        }
+    }
+
+    match (action.as_deref(), path) {
+        (Some("verify"), Some(path)) => verify(&path),
+        (Some("verify"), None) => Err("audit verify requires the path of the audit log".into()),
+        (Some(action), _) => Err(format!("unknown audit action '{}'", action).into()),
+        (None, _) => {
+            println!("{}", super::HELP);
+            Ok(())
+        }
+    }
+}
+
+// Checks every entry against its hash and the hash of the entry before it. Problems are printed
+// one per line, the log is intact when there are none.
+fn verify(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let content = std::fs::read(path)
+        .map_err(|e| format!("failed to read audit log '{}': {}", path.display(), e))?;
+    let chain = check(&content)?;
+
+    for problem in chain.problems.iter() {
+        println!("{}", problem);
+    }
+    if !chain.problems.is_empty() {
+        return Err(format!(
+            "audit log '{}' has {} problem(s)",
+            path.display(),
+            chain.problems.len()
+        )
+        .into());
+    }
+
+    match chain.head {
+        Some(head) if chain.entries > 0 => println!(
+            "audit log '{}' is intact, {} entries, last hash {}",
+            path.display(),
+            chain.entries,
+            head
+        ),
+        _ => println!("audit log '{}' is empty", path.display()),
+    }
+    Ok(())
+}
+
+// What [check] found in a log.
+struct Chain {
+    problems: Vec<String>,
+    entries: usize,
+    // The hash of the last entry, unknown when it can't be parsed.
+    head: Option<String>,
+}
+
+fn check(content: &[u8]) -> Result<Chain, serde_json::Error> {
+    let mut problems = Vec::new();
+    // Unknown after an entry that can't be parsed
+    let mut expected = Some((0, GENESIS.to_string()));
+    let mut entries = 0;
+    for (index, raw) in content.split_inclusive(|byte| *byte == b'\n').enumerate() {
+        let number = index + 1;
+        let Some(raw) = raw.strip_suffix(b"\n") else {
+            problems.push(format!("line {}: the entry is incomplete", number));
+            break;
+        };
+        let line: Line = match serde_json::from_slice(raw) {
+            Ok(line) => line,
+            Err(e) => {
+                problems.push(format!("line {}: not a valid entry: {}", number, e));
+                expected = None;
+                continue;
+            }
+        };
+        entries += 1;
+
+        // Anything but the exact serialization, eg; an added field, is a modification
+        let canonical = serde_json::to_vec(&line)?;
+        if canonical != raw || hash(&line.entry)? != line.hash {
+            problems.push(format!(
+                "line {}: entry {} was modified",
+                number, line.entry.sequence
+            ));
+        }
+        if let Some((sequence, previous)) = &expected {
+            if line.entry.sequence != *sequence {
+                problems.push(format!(
+                    "line {}: expected entry {}, found entry {}, entries are missing or reordered",
+                    number, sequence, line.entry.sequence
+                ));
+            } else if line.entry.previous != *previous {
+                problems.push(format!(
+                    "line {}: entry {} does not follow the entry before",
+                    number, line.entry.sequence
+                ));
+            }
+        }
+        expected = Some((line.entry.sequence + 1, line.hash));
+    }
+
+    Ok(Chain {
+        problems,
+        entries,
+        head: expected.map(|(_, head)| head),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(secret: &str) -> Transfer {
+        Transfer {
+            peer: "unix:pid=1,uid=0".into(),
+            secret: secret.into(),
+            digest: None,
+            size: 0,
+            destination: "/etc/db.conf".into(),
+            outcome: "delivered".into(),
+        }
+    }
+
+    // A log of the provided amount of entries, as lines.
+    fn log(entries: usize) -> Vec<Vec<u8>> {
+        // Tests run in parallel, each writes its own file
+        static LOGS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "bss-audit-{}-{}",
+            std::process::id(),
+            LOGS.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        let log = AuditLog::open(&path, Side::Receive).unwrap();
+        for index in 0..entries {
+            log.record(transfer(&format!("secret{}", index)));
+        }
+        let content = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        content
+            .split_inclusive(|byte| *byte == b'\n')
+            .map(|line| line.to_vec())
+            .collect()
+    }
+
+    fn problems(lines: &[Vec<u8>]) -> Vec<String> {
+        check(&lines.concat()).unwrap().problems
+    }
+
+    #[test]
+    fn hash_covers_every_field() {
+        let entry = |secret: &str, previous: &str| Entry {
+            sequence: 0,
+            previous: previous.into(),
+            time: "2024-05-01T12:00:00.000Z".into(),
+            side: Side::Send,
+            transfer: transfer(secret),
+        };
+
+        let digest = hash(&entry("db", GENESIS)).unwrap();
+        assert_eq!(digest, hash(&entry("db", GENESIS)).unwrap());
+        assert_ne!(digest, hash(&entry("dc", GENESIS)).unwrap());
+        assert_ne!(digest, hash(&entry("db", &digest)).unwrap());
+    }
+
+    #[test]
+    fn head_finds_the_last_entry() {
+        let path = std::env::temp_dir().join(format!("bss-audit-head-{}", std::process::id()));
+        let log = AuditLog::open(&path, Side::Send).unwrap();
+        let empty = head(&log.file.lock().unwrap()).unwrap();
+        // Spans several chunks read from the end
+        for index in 0..40 {
+            log.record(transfer(&format!("secret{}", index)));
+        }
+        let last = head(&log.file.lock().unwrap()).unwrap();
+        let chain = check(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(empty.is_none());
+        assert_eq!(last, Some((39, chain.head.unwrap())));
+        assert_eq!(chain.entries, 40);
+    }
+
+    #[test]
+    fn head_refuses_an_incomplete_last_entry() {
+        let path = std::env::temp_dir().join(format!("bss-audit-cut-{}", std::process::id()));
+        let mut lines = log(2);
+        lines[1].pop();
+        std::fs::write(&path, lines.concat()).unwrap();
+        let result = AuditLog::open(&path, Side::Send);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn verify_accepts_an_intact_log() {
+        let chain = check(&log(3).concat()).unwrap();
+
+        assert!(chain.problems.is_empty());
+        assert_eq!(chain.entries, 3);
+    }
+
+    #[test]
+    fn verify_reports_modified_entries() {
+        let mut lines = log(3);
+        lines[1] = String::from_utf8(lines[1].clone())
+            .unwrap()
+            .replace("secret1", "secret9")
+            .into_bytes();
+
+        assert_eq!(problems(&lines), vec!["line 2: entry 1 was modified"]);
+    }
+
+    #[test]
+    fn verify_reports_reordered_entries() {
+        let mut lines = log(3);
+        lines.swap(1, 2);
+
+        let problems = problems(&lines);
+        assert!(problems[0].starts_with("line 2: expected entry 1, found entry 2"));
+        assert!(problems[1].starts_with("line 3: expected entry 3, found entry 1"));
+    }
+
+    #[test]
+    fn verify_reports_an_incomplete_last_line() {
+        let mut lines = log(2);
+        lines[1].truncate(10);
+
+        assert_eq!(problems(&lines), vec!["line 2: the entry is incomplete"]);
+    }
+}
@@ -0,0 +1,99 @@
+use std::process::Stdio;
+
+// Variables of the receiver passed on to commands and hooks, everything else is cleared.
+const INHERITED_VARIABLES: &[&str] = &["PATH", "LANG", "LC_ALL", "TZ"];
+// Used when the receiver is started without PATH.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+#[derive(Debug)]
+pub enum CommandError {
+    Spawn(std::io::Error),
+    Io(std::io::Error),
+    Timeout,
+    Status(std::process::ExitStatus),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Spawn(e) => write!(f, "failed to start command: {}", e),
+            CommandError::Io(e) => write!(f, "failed to run command: {}", e),
+            CommandError::Timeout => write!(f, "command timed out"),
+            CommandError::Status(status) => write!(f, "command failed with {}", status),
+        }
+    }
+}
+
+// A command with the environment of the receiver cleared, except for [INHERITED_VARIABLES].
+pub fn command(program: &str) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(program);
+    command.env_clear();
+    for variable in INHERITED_VARIABLES {
+        if let Some(value) = std::env::var_os(variable) {
+            command.env(variable, value);
+        }
+    }
+    if std::env::var_os("PATH").is_none() {
+        command.env("PATH", DEFAULT_PATH);
+    }
+    command
+}
+
+// Commands of sinks and sources are killed when they run longer. (Default 30)
+pub fn default_timeout() -> u64 {
+    30
+}
+
+// Runs the configured command as the provided owner and group, and streams the body into its
+// standard input. The upload succeeds when the command exits successfully within the timeout.
+pub async fn run(
+    sink: &super::sink::CommandSink,
+    secret_name: &str,
+    ownership: &super::ownership::Ownership,
+    file_body: &mut (impl tokio::io::AsyncBufRead + Unpin),
+) -> Result<(), CommandError> {
+    let (program, arguments) = match sink.argv.split_first() {
+        Some(split) => split,
+        None => {
+            return Err(CommandError::Spawn(std::io::Error::other(
+                "command sink requires a non-empty argv",
+            )))
+        }
+    };
+
+    let mut child = command(program)
+        .args(arguments)
+        .uid(ownership.uid)
+        .gid(ownership.gid)
+        .current_dir("/")
+        .env("BSS_SECRET_NAME", secret_name)
+        .stdin(Stdio::piped())
+        // WARN; Output is discarded, a command echoing its input would put the secret in the logs
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(CommandError::Spawn)?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let run = async move {
+        match tokio::io::copy_buf(file_body, &mut stdin).await {
+            Ok(_) => {}
+            // The command is allowed to stop reading early, its exit status decides the outcome
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            Err(e) => return Err(CommandError::Io(e)),
+        }
+        // Signal end of input
+        drop(stdin);
+
+        child.wait().await.map_err(CommandError::Io)
+    };
+
+    let timeout = std::time::Duration::from_secs(sink.timeout_seconds);
+    match tokio::time::timeout(timeout, run).await {
+        Err(_) => Err(CommandError::Timeout),
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(status)) if !status.success() => Err(CommandError::Status(status)),
+        Ok(Ok(_)) => Ok(()),
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+// Default search paths of `LoadCredential=` and `LoadCredentialEncrypted=`, see systemd.exec(5).
+const CREDSTORE_DIRECTORY: &str = "/run/credstore";
+const CREDSTORE_ENCRYPTED_DIRECTORY: &str = "/run/credstore.encrypted";
+
+// Credentials are read by the service manager, which runs as root. The credential store must
+// not be accessible to anyone else.
+pub const CREDENTIAL_OWNERSHIP: super::ownership::Ownership = super::ownership::Ownership {
+    uid: 0,
+    gid: 0,
+    mode: 0o400,
+};
+const CREDSTORE_DIRECTORY_MODE: u32 = 0o700;
+
+// Returns the path of the credential file, creating the credential store when missing.
+pub async fn prepare_destination(
+    sink: &super::sink::CredentialSink,
+    name: &str,
+) -> std::io::Result<PathBuf> {
+    let directory = directory(sink);
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(CREDSTORE_DIRECTORY_MODE)
+        .create(&directory)
+        .await?;
+
+    Ok(directory.join(name))
+}
+
+// The directory the credentials of this sink are stored in.
+pub fn directory(sink: &super::sink::CredentialSink) -> PathBuf {
+    match (&sink.directory, sink.encrypted) {
+        (Some(directory), _) => directory.clone(),
+        (None, false) => PathBuf::from(CREDSTORE_DIRECTORY),
+        (None, true) => PathBuf::from(CREDSTORE_ENCRYPTED_DIRECTORY),
+    }
+}
+
+// Encrypts the payload into the format expected by `LoadCredentialEncrypted=`.
+pub async fn encrypt(
+    sink: &super::sink::CredentialSink,
+    name: &str,
+    payload: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+
+    let mut command = super::command::command("systemd-creds");
+    command.arg("encrypt").arg(format!("--name={}", name));
+    if let Some(with_key) = &sink.with_key {
+        command.arg(format!("--with-key={}", with_key));
+    }
+    let mut child = command
+        .arg("-")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // NOTE; Writing and reading concurrently prevents a deadlock on full pipe buffers
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let write_payload = async move {
+        stdin.write_all(payload).await?;
+        stdin.shutdown().await
+    };
+    let (write_result, output) = tokio::join!(write_payload, child.wait_with_output());
+    let output = output?;
+    write_result?;
+
+    if !output.status.success() {
+        // systemd-creds reports the cause on the first line
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!(
+            "systemd-creds encrypt failed with {}: {}",
+            output.status,
+            stderr.lines().next().unwrap_or_default()
+        )));
+    }
+
+    Ok(output.stdout)
+}
@@ -0,0 +1,98 @@
+use std::path::Path;
+
+// Marks an upload whose body is encrypted, the value names the format.
+pub const ENCRYPTION_HEADER: &str = "x-bss-encryption";
+
+// The body is an age file, see https://age-encryption.org/v1
+pub const AGE: &str = "age";
+
+// Parses an age X25519 recipient, eg; "age1..."
+pub fn parse_recipient(
+    recipient: &str,
+) -> Result<age::x25519::Recipient, Box<dyn std::error::Error>> {
+    Ok(recipient
+        .parse()
+        .map_err(|e| format!("invalid age recipient '{}': {}", recipient, e))?)
+}
+
+// Reads the age X25519 identities from a file, one "AGE-SECRET-KEY-1..." per line. Empty lines
+// and lines starting with '#' are ignored, like the identity files written by age-keygen.
+pub fn read_identities(
+    path: &Path,
+) -> Result<Vec<age::x25519::Identity>, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read age identities '{}': {}", path.display(), e))?;
+
+    let mut identities = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        identities.push(line.parse().map_err(|e| {
+            format!(
+                "invalid age identity on line {} of '{}': {}",
+                number + 1,
+                path.display(),
+                e
+            )
+        })?);
+    }
+
+    if identities.is_empty() {
+        return Err(format!("no age identities found in '{}'", path.display()).into());
+    }
+
+    Ok(identities)
+}
+
+// Encrypts the content into an age file for the recipient.
+pub fn encrypt(
+    recipient: &age::x25519::Recipient,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    use std::io::Write;
+
+    let encryptor =
+        age::Encryptor::with_recipients(std::iter::once(recipient as &dyn age::Recipient))?;
+    let mut ciphertext = Vec::with_capacity(plaintext.len() + 256);
+    let mut writer = encryptor.wrap_output(&mut ciphertext)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+
+    Ok(ciphertext)
+}
+
+// Decrypts an age file held in memory, either binary or ASCII armored.
+pub fn decrypt_in_memory(
+    identities: &[age::x25519::Identity],
+    ciphertext: &[u8],
+) -> Result<super::memory::SecretBuffer, Box<dyn std::error::Error + Send + Sync>> {
+    let decryptor = age::Decryptor::new_buffered(age::armor::ArmoredReader::new(ciphertext))?;
+    let mut reader = decryptor.decrypt(
+        identities
+            .iter()
+            .map(|identity| identity as &dyn age::Identity),
+    )?;
+    let mut plaintext = super::memory::SecretBuffer::with_capacity(ciphertext.len())?;
+    plaintext.read_from_blocking(&mut reader)?;
+
+    Ok(plaintext)
+}
+
+// Reads the header of an age file and returns a reader producing the plaintext.
+//
+// NOTE; The plaintext is authenticated per chunk, a tampered or truncated body surfaces as an
+// error of kind InvalidData while reading.
+pub async fn decrypt<'a, 'i>(
+    identities: impl Iterator<Item = &'i age::x25519::Identity>,
+    ciphertext: &'a mut (impl tokio::io::AsyncBufRead + Unpin),
+) -> Result<impl tokio::io::AsyncBufRead + Unpin + 'a, age::DecryptError> {
+    use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+    let decryptor = age::Decryptor::new_async_buffered(ciphertext.compat()).await?;
+    let plaintext =
+        decryptor.decrypt_async(identities.map(|identity| identity as &dyn age::Identity))?;
+
+    super::memory::SecretBufReader::new(plaintext.compat()).map_err(age::DecryptError::Io)
+}
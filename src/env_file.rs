@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+// Content of the environment files generated by the receiver. Each file is fully managed by the
+// receiver, variables written by earlier runs are kept until they are received again.
+#[derive(Default)]
+pub struct EnvFiles {
+    // NOTE; The lock is held while writing, this serializes replacing the same file
+    files: tokio::sync::Mutex<BTreeMap<PathBuf, BTreeMap<String, Zeroizing<String>>>>,
+}
+
+// Returns true if the key is a valid environment variable name.
+pub fn is_valid_key(key: &str) -> bool {
+    let mut characters = key.chars();
+    let first_valid = characters
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    first_valid && characters.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Converts the payload into a single line value. One trailing newline is accepted, because most
+// files end with one.
+pub fn parse_value(payload: &[u8]) -> Result<Zeroizing<String>, String> {
+    let value = std::str::from_utf8(payload).map_err(|_| "value is not valid UTF-8")?;
+    let value = value.strip_suffix('\n').unwrap_or(value);
+    if value.contains(['\n', '\r', '\0']) {
+        return Err("value must be a single line".into());
+    }
+
+    Ok(Zeroizing::new(value.to_string()))
+}
+
+impl EnvFiles {
+    // Sets the variable and atomically replaces the file with all known variables. The variables
+    // of the existing file are read on first touch, a session delivering a subset keeps the others.
+    pub async fn update(
+        &self,
+        path: &Path,
+        key: String,
+        value: Zeroizing<String>,
+        ownership: &super::ownership::Ownership,
+    ) -> std::io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut files = self.files.lock().await;
+        if !files.contains_key(path) {
+            files.insert(path.to_owned(), read_variables(path)?);
+        }
+        let variables = files.get_mut(path).expect("variables were just inserted");
+        variables.insert(key, value);
+
+        let mut staged_file = super::atomic_file::StagedFile::create(path).await?;
+        staged_file
+            .file
+            .write_all(render(variables).as_bytes())
+            .await?;
+        staged_file.commit(ownership).await
+    }
+}
+
+// Values are double quoted, which is understood by both `EnvironmentFile=` and POSIX shells.
+fn render(variables: &BTreeMap<String, Zeroizing<String>>) -> Zeroizing<String> {
+    const HEADER: &str = "# Generated by bss, manual changes are overwritten\n";
+
+    // Sized for the worst case up front, growing would leave copies of the values behind
+    let capacity = variables
+        .iter()
+        .map(|(key, value)| key.len() + 2 * value.len() + 4)
+        .sum::<usize>();
+    let mut content = Zeroizing::new(String::with_capacity(HEADER.len() + capacity));
+    content.push_str(HEADER);
+    for (key, value) in variables {
+        content.push_str(key);
+        content.push_str("=\"");
+        for c in value.chars() {
+            if matches!(c, '"' | '\\' | '$' | '`') {
+                content.push('\\');
+            }
+            content.push(c);
+        }
+        content.push_str("\"\n");
+    }
+
+    content
+}
+
+// Reads the variables back from a file generated by [render], lines of another shape are
+// skipped.
+fn parse(content: &str) -> BTreeMap<String, Zeroizing<String>> {
+    let mut variables = BTreeMap::new();
+    for line in content.lines() {
+        let Some((key, quoted)) = line.split_once("=\"") else {
+            continue;
+        };
+        let Some(quoted) = quoted.strip_suffix('"') else {
+            continue;
+        };
+        if !is_valid_key(key) {
+            continue;
+        }
+
+        let mut value = Zeroizing::new(String::with_capacity(quoted.len()));
+        let mut escaped = false;
+        for c in quoted.chars() {
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                _ => {
+                    escaped = false;
+                    value.push(c);
+                }
+            }
+        }
+        variables.insert(key.to_string(), value);
+    }
+    variables
+}
+
+// Reads the variables of the file, none when it doesn't exist yet.
+fn read_variables(path: &Path) -> std::io::Result<BTreeMap<String, Zeroizing<String>>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(parse(&Zeroizing::new(content))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Reads the value of the variable back from a file generated by [render], none when the file
+// doesn't hold it.
+pub fn read_value(path: &Path, key: &str) -> std::io::Result<Option<Zeroizing<String>>> {
+    let content = Zeroizing::new(std::fs::read_to_string(path)?);
+    Ok(parse(&content).remove(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> BTreeMap<String, Zeroizing<String>> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), Zeroizing::new(value.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn render_escapes_shell_expansion() {
+        let content = render(&variables(&[("A", r#"x"$HOME`id`\"#)]));
+        assert_eq!(content.lines().nth(1), Some(r#"A="x\"\$HOME\`id\`\\""#));
+    }
+
+    #[test]
+    fn render_and_parse_round_trip() {
+        let variables = variables(&[
+            ("PGPASSWORD", r#"p"a$s`w\o'rd"#),
+            ("EMPTY", ""),
+            ("TRAILING", "ends with \\"),
+            ("_KEY1", "plain"),
+        ]);
+        assert_eq!(parse(&render(&variables)), variables);
+    }
+
+    #[test]
+    fn read_value_round_trip() {
+        let path = std::env::temp_dir().join(format!("bss-env-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            render(&variables(&[("A", "1"), ("B", r#"two "2""#)])).as_bytes(),
+        )
+        .unwrap();
+        let a = read_value(&path, "A").unwrap();
+        let b = read_value(&path, "B").unwrap();
+        let c = read_value(&path, "C").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(a.as_deref().map(String::as_str), Some("1"));
+        assert_eq!(b.as_deref().map(String::as_str), Some(r#"two "2""#));
+        assert!(c.is_none());
+    }
+
+    #[test]
+    fn parse_value_accepts_one_trailing_newline() {
+        assert_eq!(parse_value(b"secret\n").unwrap().as_str(), "secret");
+        assert_eq!(parse_value(b"secret").unwrap().as_str(), "secret");
+        assert!(parse_value(b"secret\n\n").is_err());
+    }
+
+    #[test]
+    fn parse_value_rejects_multiple_lines() {
+        assert!(parse_value(b"a\nb").is_err());
+        assert!(parse_value(b"a\rb").is_err());
+        assert!(parse_value(b"a\0b").is_err());
+        assert!(parse_value(&[0xff, 0xfe]).is_err());
+    }
+}
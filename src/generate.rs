@@ -0,0 +1,333 @@
+use std::path::Path;
+
+// Describes random secret material created by the sender at seed time.
+//
+// eg;
+// source = { type = "generate", kind = "random_bytes", length = 32, encoding = "hex" }
+// source = { type = "generate", kind = "password", length = 24, persist_path = "/var/lib/bss/vm1/db-password" }
+// source = { type = "generate", kind = "x25519", persist_path = "/var/lib/bss/vm1/wireguard" }
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Generator {
+    RandomBytes {
+        length: usize,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    // Alphanumeric password
+    Password {
+        length: usize,
+    },
+    // OpenSSH formatted private key, eg for SSH host keys.
+    Ed25519,
+    // Base64 encoded private key, eg for WireGuard.
+    X25519,
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Raw,
+    Hex,
+    Base64,
+}
+
+// Freshly generated secret material.
+struct Generated {
+    secret: Vec<u8>,
+    // The public half of a generated keypair, stored next to the persisted secret so the host can
+    // configure the other side of the connection.
+    public: Option<Vec<u8>>,
+}
+
+// Returns the previously persisted value if it exists, otherwise generates a new value and persists
+// it when a path is provided. This allows a guest to receive the same secret across reboots.
+pub async fn load_or_generate(
+    generator: &Generator,
+    persist_path: Option<&Path>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let persist_path = match persist_path {
+        Some(path) => path,
+        None => return Ok(generator.generate()?.secret),
+    };
+
+    match tokio::fs::read(persist_path).await {
+        // A persisted value that doesn't look generated is never passed on, eg; cut short by a
+        // crash of an earlier version
+        Ok(secret) => {
+            return match generator.check(&secret) {
+                Ok(()) => Ok(secret),
+                Err(e) => Err(format!(
+                    "persisted value '{}' is invalid, {}. Remove it to generate a new value",
+                    persist_path.display(),
+                    e
+                ))?,
+            };
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(format!(
+                "failed to read '{}': {}",
+                persist_path.display(),
+                e
+            ))?;
+        }
+    }
+
+    let generated = generator.generate()?;
+    if let Some(public) = &generated.public {
+        let mut public_path = persist_path.as_os_str().to_owned();
+        public_path.push(".pub");
+        persist(Path::new(&public_path), public, 0o644).await?;
+    }
+    // NOTE; Secret is persisted last, a missing public key is never mistaken for a completed run
+    persist(persist_path, &generated.secret, 0o600).await?;
+
+    Ok(generated.secret)
+}
+
+// Atomically replaces the file, a crash or full disk never leaves a partial value behind.
+async fn persist(
+    path: &Path,
+    data: &[u8],
+    mode: u32,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use tokio::io::AsyncWriteExt;
+
+    let ownership = super::ownership::Ownership {
+        uid: unsafe { libc::geteuid() },
+        gid: unsafe { libc::getegid() },
+        mode,
+    };
+    let persist = async {
+        let mut staged_file = super::atomic_file::StagedFile::create(path).await?;
+        staged_file.file.write_all(data).await?;
+        staged_file.commit(&ownership).await
+    };
+    persist
+        .await
+        .map_err(|e| format!("failed to persist '{}': {}", path.display(), e))?;
+
+    Ok(())
+}
+
+const PASSWORD_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+// Largest multiple of the alphabet length that fits in a byte, bytes above are rejected to keep
+// the character distribution uniform.
+const PASSWORD_LIMIT: u8 = (256 / PASSWORD_ALPHABET.len() * PASSWORD_ALPHABET.len()) as u8;
+
+// Maps random bytes to password characters, dropping the rejected bytes.
+fn password_characters(random: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    random
+        .iter()
+        .filter(|&&byte| byte < PASSWORD_LIMIT)
+        .map(|&byte| PASSWORD_ALPHABET[byte as usize % PASSWORD_ALPHABET.len()])
+}
+
+impl Generator {
+    // Checks the settings during manifest validation.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Generator::RandomBytes { length: 0, .. } | Generator::Password { length: 0 } => {
+                Err("a generated length of 0".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Checks a persisted value has the shape of a value of this generator.
+    fn check(&self, secret: &[u8]) -> Result<(), String> {
+        use base64::Engine;
+        let base64 = base64::engine::general_purpose::STANDARD;
+
+        if secret.is_empty() {
+            return Err("it is empty".to_string());
+        }
+        let expected_length = match self {
+            Generator::RandomBytes { length, encoding } => match encoding {
+                Encoding::Raw => Some(*length),
+                Encoding::Hex => Some(length * 2),
+                Encoding::Base64 => Some(length.div_ceil(3) * 4),
+            },
+            Generator::Password { length } => Some(*length),
+            Generator::Ed25519 => None,
+            Generator::X25519 => Some(44),
+        };
+        if let Some(expected_length) = expected_length {
+            if secret.len() != expected_length {
+                return Err(format!(
+                    "it holds {} bytes instead of {}",
+                    secret.len(),
+                    expected_length
+                ));
+            }
+        }
+
+        let valid = match self {
+            Generator::RandomBytes {
+                encoding: Encoding::Raw,
+                ..
+            } => true,
+            Generator::RandomBytes {
+                encoding: Encoding::Hex,
+                ..
+            } => secret.iter().all(u8::is_ascii_hexdigit),
+            Generator::RandomBytes {
+                encoding: Encoding::Base64,
+                ..
+            } => base64.decode(secret).is_ok(),
+            Generator::Password { .. } => {
+                secret.iter().all(|byte| PASSWORD_ALPHABET.contains(byte))
+            }
+            Generator::Ed25519 => ssh_key::PrivateKey::from_openssh(secret).is_ok(),
+            Generator::X25519 => base64.decode(secret).is_ok_and(|key| key.len() == 32),
+        };
+        match valid {
+            true => Ok(()),
+            false => Err("its content is malformed".to_string()),
+        }
+    }
+
+    fn generate(&self) -> Result<Generated, Box<dyn std::error::Error + Send + Sync>> {
+        use base64::Engine;
+        let base64 = base64::engine::general_purpose::STANDARD;
+
+        match self {
+            Generator::RandomBytes { length, encoding } => {
+                let mut bytes = vec![0u8; *length];
+                getrandom::getrandom(&mut bytes)?;
+                let secret = match encoding {
+                    Encoding::Raw => bytes,
+                    Encoding::Hex => bytes
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect::<String>()
+                        .into_bytes(),
+                    Encoding::Base64 => base64.encode(&bytes).into_bytes(),
+                };
+                Ok(Generated {
+                    secret,
+                    public: None,
+                })
+            }
+            Generator::Password { length } => {
+                let mut secret = Vec::with_capacity(*length);
+                let mut buffer = [0u8; 64];
+                while secret.len() < *length {
+                    getrandom::getrandom(&mut buffer)?;
+                    secret.extend(password_characters(&buffer).take(*length - secret.len()));
+                }
+                Ok(Generated {
+                    secret,
+                    public: None,
+                })
+            }
+            Generator::Ed25519 => {
+                use ssh_key::private::{Ed25519Keypair, PrivateKey};
+                use ssh_key::LineEnding;
+
+                let mut seed = [0u8; 32];
+                getrandom::getrandom(&mut seed)?;
+                let private_key = PrivateKey::from(Ed25519Keypair::from_seed(&seed));
+                let secret = private_key.to_openssh(LineEnding::LF)?.as_bytes().to_vec();
+                let public = private_key.public_key().to_openssh()?.into_bytes();
+                Ok(Generated {
+                    secret,
+                    public: Some(public),
+                })
+            }
+            Generator::X25519 => {
+                let mut bytes = [0u8; 32];
+                getrandom::getrandom(&mut bytes)?;
+                // Clamp like `wg genkey` does
+                bytes[0] &= 248;
+                bytes[31] &= 127;
+                bytes[31] |= 64;
+                let private_key = x25519_dalek::StaticSecret::from(bytes);
+                let public_key = x25519_dalek::PublicKey::from(&private_key);
+                Ok(Generated {
+                    secret: base64.encode(private_key.as_bytes()).into_bytes(),
+                    public: Some(base64.encode(public_key.as_bytes()).into_bytes()),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_rejects_bytes_above_limit() {
+        let random: Vec<u8> = (0..=255).collect();
+        let characters: Vec<u8> = password_characters(&random).collect();
+        assert_eq!(characters.len(), PASSWORD_LIMIT as usize);
+        assert!(password_characters(&[PASSWORD_LIMIT, 255]).next().is_none());
+    }
+
+    #[test]
+    fn password_characters_are_uniform() {
+        let random: Vec<u8> = (0..=255).collect();
+        let mut counts = std::collections::BTreeMap::new();
+        for character in password_characters(&random) {
+            *counts.entry(character).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), PASSWORD_ALPHABET.len());
+        assert!(counts.values().all(|&count| count == 4));
+    }
+
+    #[test]
+    fn generated_values_pass_check() {
+        let generators = [
+            Generator::RandomBytes {
+                length: 32,
+                encoding: Encoding::Raw,
+            },
+            Generator::RandomBytes {
+                length: 31,
+                encoding: Encoding::Hex,
+            },
+            Generator::RandomBytes {
+                length: 31,
+                encoding: Encoding::Base64,
+            },
+            Generator::Password { length: 24 },
+            Generator::Ed25519,
+            Generator::X25519,
+        ];
+        for generator in generators {
+            let generated = generator.generate().unwrap();
+            assert_eq!(
+                generator.check(&generated.secret),
+                Ok(()),
+                "{:?}",
+                generator
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_zero_length() {
+        let generator = Generator::RandomBytes {
+            length: 0,
+            encoding: Encoding::Hex,
+        };
+        assert!(generator.validate().is_err());
+        assert!(Generator::Password { length: 0 }.validate().is_err());
+        assert!(Generator::Password { length: 1 }.validate().is_ok());
+    }
+
+    #[test]
+    fn check_rejects_truncated_values() {
+        let generator = Generator::Password { length: 24 };
+        let secret = generator.generate().unwrap().secret;
+        assert!(generator.check(&secret[..12]).is_err());
+        assert!(generator.check(b"").is_err());
+
+        let generator = Generator::Ed25519;
+        let secret = generator.generate().unwrap().secret;
+        assert!(generator.check(&secret[..secret.len() / 2]).is_err());
+    }
+}
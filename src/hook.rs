@@ -0,0 +1,108 @@
+use std::process::Stdio;
+
+// Hooks are killed when they run longer.
+const HOOK_TIMEOUT_SECONDS: u64 = 120;
+// Bytes of the error output of a failed hook that are logged, the rest is discarded.
+const HOOK_OUTPUT_LIMIT: u64 = 512;
+
+// An action executed by the receiver after secrets are delivered, to tell consumers about the
+// new content.
+//
+// eg;
+// on_delivered = [{ type = "restart", unit = "nginx.service" }]
+// on_delivered = [{ type = "command", argv = ["/usr/local/bin/rotate-db-password"] }]
+// on_delivered = [{ type = "command", argv = ["rebuild-cache"], writable_paths = ["/var/cache/app"] }]
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Hook {
+    Command {
+        argv: Vec<String>,
+        // Files and directories the command writes to, see [super::sink::CommandSink].
+        #[serde(default)]
+        writable_paths: Vec<std::path::PathBuf>,
+    },
+    Reload {
+        unit: String,
+    },
+    Restart {
+        unit: String,
+    },
+}
+
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hook::Command { argv, .. } => write!(f, "command {:?}", argv),
+            Hook::Reload { unit } => write!(f, "reload {}", unit),
+            Hook::Restart { unit } => write!(f, "restart {}", unit),
+        }
+    }
+}
+
+impl Hook {
+    // Runs the hook with the provided variables added to its environment.
+    pub async fn run(&self, environment: &[(&str, String)]) -> Result<(), String> {
+        let argv = match self {
+            Hook::Command { argv, .. } => argv.clone(),
+            // NOTE; Unit names starting with a dash are not interpreted as options after "--"
+            Hook::Reload { unit } => vec![
+                "systemctl".into(),
+                "reload".into(),
+                "--".into(),
+                unit.clone(),
+            ],
+            Hook::Restart { unit } => {
+                vec![
+                    "systemctl".into(),
+                    "restart".into(),
+                    "--".into(),
+                    unit.clone(),
+                ]
+            }
+        };
+        let (program, arguments) = argv.split_first().ok_or("hook requires a non-empty argv")?;
+
+        let mut child = super::command::command(program)
+            .args(arguments)
+            .envs(environment.iter().map(|(key, value)| (key, value)))
+            .current_dir("/")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("failed to start: {}", e))?;
+
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let run = async {
+            use tokio::io::AsyncReadExt;
+
+            let mut output = Vec::new();
+            let read = async {
+                let _ = (&mut stderr)
+                    .take(HOOK_OUTPUT_LIMIT)
+                    .read_to_end(&mut output)
+                    .await;
+                // Keep the pipe drained, a blocked hook would run into the timeout
+                let _ = tokio::io::copy(&mut stderr, &mut tokio::io::sink()).await;
+            };
+            let (_, status) = tokio::join!(read, child.wait());
+            (status, output)
+        };
+
+        let timeout = std::time::Duration::from_secs(HOOK_TIMEOUT_SECONDS);
+        match tokio::time::timeout(timeout, run).await {
+            Err(_) => Err("timed out".into()),
+            Ok((Err(e), _)) => Err(format!("failed to wait: {}", e)),
+            Ok((Ok(status), output)) if !status.success() => {
+                // NOTE; Hooks don't receive secret content, their output is logged length limited
+                log::warn!(
+                    hook:% = self, stderr = String::from_utf8_lossy(&output).trim_end();
+                    "Output of failed hook"
+                );
+                Err(format!("failed with {}", status))
+            }
+            Ok((Ok(_), _)) => Ok(()),
+        }
+    }
+}
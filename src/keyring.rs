@@ -0,0 +1,202 @@
+use std::ffi::{CStr, CString};
+use std::io;
+
+// REF; linux/keyctl.h
+const KEY_SPEC_PROCESS_KEYRING: libc::c_long = -2;
+const KEY_SPEC_SESSION_KEYRING: libc::c_long = -3;
+const KEY_SPEC_USER_KEYRING: libc::c_long = -4;
+const KEYCTL_SETPERM: libc::c_long = 5;
+const KEYCTL_SEARCH: libc::c_long = 10;
+const KEYCTL_GET_PERSISTENT: libc::c_long = 22;
+
+const USER_KEY_TYPE: &CStr = c"user";
+const KEYRING_KEY_TYPE: &CStr = c"keyring";
+
+// Stores the payload into the keyring selected by the sink. Adding a key with an existing
+// description atomically replaces its content.
+//
+// NOTE; Keys are added by a child running as the owner, so the owner owns them and the receiver
+// keeps no capability to change the owner of keys. Without root the receiver only adds keys it
+// owns itself
+pub fn store(
+    sink: &super::sink::KeyringSink,
+    description: &str,
+    payload: &[u8],
+    ownership: &super::ownership::Ownership,
+) -> io::Result<()> {
+    let permissions = match &sink.permissions {
+        Some(permissions) => Some(
+            u32::from_str_radix(permissions.trim_start_matches("0x"), 16).map_err(|_| {
+                io::Error::other(format!("invalid key permissions '{}'", permissions))
+            })?,
+        ),
+        None => None,
+    };
+    let request = Request {
+        keyring: &sink.keyring,
+        keyring_name: sink.keyring_name.as_deref().map(c_string).transpose()?,
+        description: c_string(description)?,
+        payload,
+        permissions,
+    };
+    let switch = unsafe { (libc::geteuid(), libc::getegid()) } != (ownership.uid, ownership.gid);
+    if switch && unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::other(format!(
+            "keyring ownership requires root, the key is owned by {}:{}",
+            ownership.uid, ownership.gid
+        )));
+    }
+
+    // WARN; Only async-signal-safe calls are allowed in the child, everything is prepared before
+    // forking
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            let switched = match switch {
+                true => switch_account(ownership),
+                false => Ok(()),
+            };
+            let code = match switched.and_then(|()| request.store()) {
+                Ok(()) => 0,
+                Err(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            };
+            unsafe { libc::_exit(code) }
+        }
+        child => wait(child),
+    }
+}
+
+// What the child adds. The user and persistent keyrings are those of the owner, the session
+// keyring is the one the receiver inherited.
+struct Request<'a> {
+    keyring: &'a super::sink::Keyring,
+    keyring_name: Option<CString>,
+    description: CString,
+    payload: &'a [u8],
+    permissions: Option<u32>,
+}
+
+impl Request<'_> {
+    fn store(&self) -> io::Result<()> {
+        let mut keyring = match self.keyring {
+            super::sink::Keyring::User => KEY_SPEC_USER_KEYRING,
+            super::sink::Keyring::Session => KEY_SPEC_SESSION_KEYRING,
+            // NOTE; -1 selects the persistent keyring of the calling user
+            super::sink::Keyring::Persistent => {
+                keyctl(KEYCTL_GET_PERSISTENT, -1, KEY_SPEC_PROCESS_KEYRING, 0)?
+            }
+        };
+        if let Some(name) = &self.keyring_name {
+            keyring = find_or_create_keyring(keyring, name)?;
+        }
+
+        let key = add_key(USER_KEY_TYPE, &self.description, self.payload, keyring)?;
+        if let Some(permissions) = self.permissions {
+            keyctl(KEYCTL_SETPERM, key, permissions as libc::c_long, 0)?;
+        }
+        Ok(())
+    }
+}
+
+// Switches every user and group id of the child to the owner of the secret.
+fn switch_account(ownership: &super::ownership::Ownership) -> io::Result<()> {
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) != 0
+            || libc::setresgid(ownership.gid, ownership.gid, ownership.gid) != 0
+            || libc::setresuid(ownership.uid, ownership.uid, ownership.uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// The child exits with the errno of the failed call.
+fn wait(child: libc::pid_t) -> io::Result<()> {
+    let mut status = 0;
+    while unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    match (libc::WIFEXITED(status), libc::WEXITSTATUS(status)) {
+        (true, 0) => Ok(()),
+        (true, code) => Err(io::Error::from_raw_os_error(code)),
+        (false, _) => Err(io::Error::other(format!(
+            "the child adding the key ended with status {}",
+            status
+        ))),
+    }
+}
+
+fn find_or_create_keyring(parent: libc::c_long, name: &CStr) -> io::Result<libc::c_long> {
+    let found = keyctl(
+        KEYCTL_SEARCH,
+        parent,
+        KEYRING_KEY_TYPE.as_ptr() as libc::c_long,
+        name.as_ptr() as libc::c_long,
+    );
+
+    match found {
+        Ok(keyring) => Ok(keyring),
+        Err(e) if e.raw_os_error() == Some(libc::ENOKEY) => {
+            add_key(KEYRING_KEY_TYPE, name, &[], parent)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn add_key(
+    key_type: &CStr,
+    description: &CStr,
+    payload: &[u8],
+    keyring: libc::c_long,
+) -> io::Result<libc::c_long> {
+    let payload_pointer = match payload.is_empty() {
+        true => std::ptr::null(),
+        false => payload.as_ptr(),
+    };
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            payload_pointer,
+            payload.len(),
+            keyring,
+        )
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        key => Ok(key),
+    }
+}
+
+fn keyctl(
+    operation: libc::c_long,
+    argument2: libc::c_long,
+    argument3: libc::c_long,
+    argument4: libc::c_long,
+) -> io::Result<libc::c_long> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            operation,
+            argument2,
+            argument3,
+            argument4,
+            0,
+        )
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        value => Ok(value),
+    }
+}
+
+fn c_string(value: &str) -> io::Result<CString> {
+    CString::new(value).map_err(|_| io::Error::other(format!("'{}' contains a NUL byte", value)))
+}
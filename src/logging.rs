@@ -0,0 +1,212 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// How log records are written to standard error, one record per line.
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    // eg; time=2024-05-01T12:00:00.000Z level=info target=bss::receive msg="Upload delivered" secret=db
+    Logfmt,
+    // eg; {"time":"2024-05-01T12:00:00.000Z","level":"info","target":"bss::receive","msg":"Upload delivered","secret":"db"}
+    Json,
+}
+
+// Records of dependencies never pass this level, whatever is requested. Their debug and trace
+// records can contain headers and buffers, which hold tokens and secret content.
+const DEPENDENCY_LEVEL: log::LevelFilter = log::LevelFilter::Warn;
+
+static LOGGER: Logger = Logger {
+    json: AtomicBool::new(false),
+};
+
+struct Logger {
+    json: AtomicBool,
+}
+
+// Installs the logger. The level of records of this program is read from RUST_LOG, eg;
+// "debug", and defaults to "info".
+//
+// WARN; Records carry names, peers, sizes and outcomes. Never log secret content or tokens,
+// not even at trace level!
+pub fn init() {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(log::LevelFilter::Info);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+pub fn set_format(format: Format) {
+    LOGGER
+        .json
+        .store(matches!(format, Format::Json), Ordering::Relaxed);
+}
+
+// Parses the argument of --log-format.
+pub fn parse_format(value: &str) -> Result<Format, Box<dyn std::error::Error>> {
+    match value {
+        "logfmt" => Ok(Format::Logfmt),
+        "json" => Ok(Format::Json),
+        value => Err(format!(
+            "unknown log format '{}', expected 'logfmt' or 'json'",
+            value
+        )
+        .into()),
+    }
+}
+
+fn is_own(target: &str) -> bool {
+    target == "bss" || target.starts_with("bss::")
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        is_own(metadata.target()) || metadata.level() <= DEPENDENCY_LEVEL
+    }
+
+    fn log(&self, record: &log::Record) {
+        use std::io::Write;
+
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut fields = vec![
+            ("time".to_string(), Field::Text(timestamp())),
+            (
+                "level".to_string(),
+                Field::Text(record.level().as_str().to_lowercase()),
+            ),
+            (
+                "target".to_string(),
+                Field::Text(record.target().to_string()),
+            ),
+            ("msg".to_string(), Field::Text(record.args().to_string())),
+        ];
+        let mut visitor = Collect(&mut fields);
+        let _ = record.key_values().visit(&mut visitor);
+
+        let line = match self.json.load(Ordering::Relaxed) {
+            true => render_json(&fields),
+            false => render_logfmt(&fields),
+        };
+        // NOTE; A single write per line, records of concurrent threads don't interleave
+        let _ = std::io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+enum Field {
+    Text(String),
+    // Numbers and booleans, written without quotes.
+    Literal(String),
+}
+
+struct Collect<'a>(&'a mut Vec<(String, Field)>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for Collect<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let field = match (value.to_u64(), value.to_i64(), value.to_bool()) {
+            (Some(number), _, _) => Field::Literal(number.to_string()),
+            (_, Some(number), _) => Field::Literal(number.to_string()),
+            (_, _, Some(boolean)) => Field::Literal(boolean.to_string()),
+            _ => Field::Text(value.to_string()),
+        };
+        self.0.push((key.as_str().to_string(), field));
+        Ok(())
+    }
+}
+
+fn render_logfmt(fields: &[(String, Field)]) -> String {
+    let mut line = String::new();
+    for (index, (key, field)) in fields.iter().enumerate() {
+        if index > 0 {
+            line.push(' ');
+        }
+        let _ = write!(line, "{}=", key);
+        match field {
+            Field::Literal(value) => line.push_str(value),
+            Field::Text(value) if needs_quotes(value) => {
+                line.push('"');
+                for character in value.chars() {
+                    match character {
+                        '"' => line.push_str("\\\""),
+                        '\\' => line.push_str("\\\\"),
+                        '\n' => line.push_str("\\n"),
+                        character => line.push(character),
+                    }
+                }
+                line.push('"');
+            }
+            Field::Text(value) => line.push_str(value),
+        }
+    }
+    line.push('\n');
+    line
+}
+
+fn needs_quotes(value: &str) -> bool {
+    value.is_empty()
+        || value
+            .chars()
+            .any(|character| character <= ' ' || matches!(character, '"' | '=' | '\\'))
+}
+
+fn render_json(fields: &[(String, Field)]) -> String {
+    let mut line = String::from("{");
+    for (index, (key, field)) in fields.iter().enumerate() {
+        if index > 0 {
+            line.push(',');
+        }
+        let value = match field {
+            Field::Literal(value) => value.clone(),
+            Field::Text(value) => serde_json::Value::from(value.as_str()).to_string(),
+        };
+        let key = serde_json::Value::from(key.as_str());
+        let _ = write!(line, "{}:{}", key, value);
+    }
+    line.push_str("}\n");
+    line
+}
+
+// The current time in RFC 3339 format, UTC with millisecond precision.
+pub fn timestamp() -> String {
+    let elapsed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = elapsed.as_secs();
+    let (days, time_of_day) = (seconds / 86400, seconds % 86400);
+
+    // REF; https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60,
+        elapsed.subsec_millis()
+    )
+}
@@ -2,13 +2,19 @@ const HELP: &str = "\
 bss [--port <u32>] [--timeout <u32>] [--help] [COMMAND] MANIFEST_FILE_PATH
 
 OPTIONS:
-    --vsock-address <TODO>
-    --unix-socket   <TODO>
-    --ip-address    <TODO>
+    --vsock-address <CID>   The vsock CID to listen on ('any' for every CID) or to connect to.
+    --unix-socket <PATH>    The UNIX socket path to listen on or to connect to. (Default /tmp/warp.sock)
+    --ip-address <IP>       The IP address to listen on or to connect to.
     -p, --port      The port number to listen/connect to. (Default {})
 
-    -t, --timeout   The amount of seconds to block waiting until a succesful connection is setup between sender and receiver. (Default {})
+    --tls-cert <PATH>       PEM certificate chain presented to the peer. Enables mutual TLS.
+    --tls-key <PATH>        PEM private key of the certificate.
+    --tls-ca <PATH>         PEM CA certificates that issue the certificate of the peer.
+    --tls-pin <HEX>         SHA-256 digest of the SubjectPublicKeyInfo of an acceptable peer certificate. Can be repeated.
+
+    -t, --timeout   The amount of seconds to block waiting until a succesful connection is setup between sender and receiver. The receiver stops when the session is not completed within it. (Default {})
     -b, --bytes-max The per-transferred-file maximum byte size limit. (Default {})
+    --log-format <FORMAT>   Format of the log records on standard error, 'logfmt' or 'json'. The level is read from RUST_LOG. (Default logfmt)
     --help          Print this help message and exit.
 
 COMMANDS:
@@ -16,7 +22,36 @@ COMMANDS:
 
     receive     Opens a new socket to receive and store files according to the manifest.
 
+    audit verify <PATH>
+                Checks the chain of an audit log and reports modified, missing or reordered entries.
+
+    verify      Compares the delivered secrets against the manifest. Reports per secret as JSON whether the destination exists, its ownership, mode and content digest, and fails on drift.
+
+COMMAND OPTIONS:
+    --cid <u32>         The vsock CID of the guest. The sender requires it to select secrets targeting a CID, the receiver detects it by default.
+    --hostname <NAME>   The hostname of the guest. The sender requires it to select secrets targeting a hostname, the receiver detects it by default.
+    --role <NAME>       A role label held by the guest. Can be repeated.
+    --token-file <PATH> A file containing the pre-shared token that authenticates the sender.
+    --token-cmdline <KEY>
+                        receive: Read the pre-shared token from the kernel command line argument KEY=<token>.
+    --hmac              Sign every request with the pre-shared token instead of presenting it, which prevents replaying observed requests.
+    --age-identity <PATH>
+                        receive: A file of age X25519 identities decrypting secrets with a recipient. Can be repeated.
+    --tls-server-name <NAME>
+                        send: The name the receiver certificate must be issued for when verified by CA. (Default bss)
+    --worker-user <NAME>
+                        receive: The unprivileged account serving the network when started as root. Delivery stays with a privileged process. (Default nobody)
+    --seccomp-log       receive: Log syscalls outside the allowlist of the network worker instead of killing it.
+    --metrics-address <IP:PORT>
+                        receive: Serve Prometheus metrics at /metrics over HTTP on this TCP address, separate from the secret socket.
+    --dry-run           send: Open every source and check sizes without connecting. receive: Check destinations, ownership and modes without listening. Prints the plan per secret as JSON.
+    --plan <PATH>       verify: The plan printed by 'send --dry-run', content is compared against its digests.
+    --audit-log <PATH>  Append an entry for every transfer to the hash-chained audit log at PATH.
+
 NOTE: The connection addresses are tried in the order VSOCK network > UNIX socket > IP network. The first argument provided in that order will be used for creating a connection.
+NOTE: When started by a systemd socket unit, the receiver listens on the passed socket instead. Of several passed sockets it picks the one with FileDescriptorName=receive.
+NOTE: The chain of an audit log has no key, whoever can write the log can rewrite it entirely with a valid chain. Keep the last hash reported by 'audit verify' out of reach of the host and check later logs still contain it.
+NOTE: TLS requires --tls-cert, --tls-key and at least one of --tls-ca or --tls-pin on both sides. A peer is accepted when its key is pinned or its certificate is issued by the CA.
 ERROR: UNIX sockets will not work on non-UNIX operating systems.
 ";
 
@@ -37,27 +72,71 @@ const DEFAULT_TIMEOUT: u32 = 3600;
 // the receive side.
 const DEFAULT_MAX_TRASMISSION_BYTES: u32 = 1024 * 1024;
 
-type StateType = ();
+type StateType = receive::ReceiverState;
 
 #[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct Manifest {
     secrets: Vec<Secret>,
+    // Hooks executed by the receiver once per session, after the hooks of the delivered secrets.
+    #[serde(default)]
+    on_delivered: Vec<hook::Hook>,
+    // The default recipient for secrets without a recipient of their own.
+    #[serde(default)]
+    recipient: Option<String>,
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct Secret {
     name: String,
-    source_path: std::path::PathBuf,
-    destination_path: std::path::PathBuf,
+    // Shorthand for a source, see [source::Source::from_path].
+    #[serde(default)]
+    source_path: Option<std::path::PathBuf>,
+    // Only used by the sender, the receiver accepts manifests without sources.
+    #[serde(default)]
+    source: Option<source::Source>,
+    // A path ending with a slash is a directory receiving every file of a directory or glob source.
+    // Required by sinks that write to the destination path.
+    #[serde(default)]
+    destination_path: Option<std::path::PathBuf>,
     owner: String,
     group: String,
     mode: String,
+    // Restricts the guests this secret is meant for. Secrets without a target are
+    // meant for every guest.
+    #[serde(default)]
+    target: Option<selector::Selector>,
+    // How the receiver delivers the secret, defaults to writing the destination path.
+    #[serde(default)]
+    sink: sink::Sink,
+    // Hooks executed by the receiver when the session completes, if this secret was delivered.
+    #[serde(default)]
+    on_delivered: Vec<hook::Hook>,
+    // The age X25519 recipient of the guest. The sender encrypts the secret to it and the receiver
+    // refuses the secret in plaintext.
+    #[serde(default)]
+    recipient: Option<String>,
+}
+
+impl Secret {
+    // Directory entries fan out into one transfer per file, named "<name>/<relative path>".
+    fn is_tree(&self) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        self.destination_path
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().as_bytes().ends_with(b"/"))
+    }
 }
 
 struct GlobalSettings {
     timeout_seconds: u32,
     socket_port: u32,
     max_transmission_bytes: u32,
+    vsock_address: Option<u32>,
+    unix_socket: Option<std::path::PathBuf>,
+    ip_address: Option<std::net::IpAddr>,
+    tls: tls::TlsSettings,
 }
 
 // Implements the receive side, aka the HTTP (and connection) server.
@@ -66,19 +145,109 @@ mod receive;
 // Implements the send side, aka the HTTP client.
 mod send;
 
+// Implements retrieving secret content on the send side.
+mod source;
+
+// Implements secret material generated on the send side.
+mod generate;
+
+// Implements resolving owner, group and mode of delivered secrets.
+mod ownership;
+
+// Implements atomic replacement of destination files.
+mod atomic_file;
+
+// Implements the delivery options on the receive side.
+mod sink;
+
+// Implements storing secrets into the kernel keyring.
+mod keyring;
+
+// Implements storing secrets as systemd credentials.
+mod credential;
+
+// Implements generating environment files.
+mod env_file;
+
+// Implements piping secrets into commands.
+mod command;
+
+// Implements post-delivery hooks.
+mod hook;
+
+// Implements tracking and completion of a receive session.
+mod session;
+
+// Implements pre-shared token authentication.
+mod token;
+
+// Implements HMAC request signing.
+mod signature;
+
+// Implements matching of secrets against the identity of a guest.
+mod selector;
+
+// Implements locked and wiped memory for secret material.
+mod memory;
+
+// Implements encryption of secrets to the guest.
+mod encryption;
+
+// Implements the split of the receiver into an unprivileged worker and a privileged writer.
+mod privsep;
+
+// Implements dropping capabilities and filtering syscalls after setup.
+mod sandbox;
+
+// Implements selecting, listening on and connecting to the transport.
+mod transport;
+
+// Implements socket activation by the service manager.
+mod systemd;
+
+// Implements mutual TLS on top of the transport.
+mod tls;
+
+// Implements structured logging.
+mod logging;
+
+// Implements the hash-chained audit log of transfers.
+mod audit;
+
+// Implements the metrics of the receive side.
+mod metrics;
+
+// Implements the output of dry runs.
+mod plan;
+
+// Implements the comparison of delivered secrets against the manifest.
+mod verify;
+
 #[cfg(unix)]
 // Implements unix sockets as underlying transport mechanism.
 mod unix_socket;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() {
+    logging::init();
+    if let Err(e) = run() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     use lexopt::prelude::*;
 
-    pretty_env_logger::init();
+    memory::disable_core_dumps();
 
     let mut settings = GlobalSettings {
         timeout_seconds: DEFAULT_TIMEOUT,
         socket_port: DEFAULT_LISTEN_ADDRESS,
         max_transmission_bytes: DEFAULT_MAX_TRASMISSION_BYTES,
+        vsock_address: None,
+        unix_socket: None,
+        ip_address: None,
+        tls: Default::default(),
     };
 
     let mut parser = lexopt::Parser::from_env();
@@ -97,6 +266,34 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             Short('b') | Long("bytes-max") => {
                 settings.max_transmission_bytes = parser.value()?.parse()?;
             }
+            Long("vsock-address") => {
+                settings.vsock_address =
+                    Some(transport::parse_vsock_cid(&parser.value()?.string()?)?);
+            }
+            Long("unix-socket") => {
+                settings.unix_socket = Some(parser.value()?.into());
+            }
+            Long("ip-address") => {
+                settings.ip_address = Some(parser.value()?.parse()?);
+            }
+            Long("tls-cert") => {
+                settings.tls.certificate = Some(parser.value()?.into());
+            }
+            Long("tls-key") => {
+                settings.tls.key = Some(parser.value()?.into());
+            }
+            Long("tls-ca") => {
+                settings.tls.ca = Some(parser.value()?.into());
+            }
+            Long("log-format") => {
+                logging::set_format(logging::parse_format(&parser.value()?.string()?)?);
+            }
+            Long("tls-pin") => {
+                settings
+                    .tls
+                    .pins
+                    .push(tls::parse_pin(&parser.value()?.string()?)?);
+            }
             Value(value) => {
                 let value = value.string()?;
                 match value.as_str() {
@@ -106,6 +303,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     "send" => {
                         return send::client_main(settings, parser);
                     }
+                    "audit" => {
+                        return audit::audit_main(parser);
+                    }
+                    "verify" => {
+                        return verify::verify_main(parser);
+                    }
                     value => {
                         return Err(format!("unknown subcommand '{}'", value).into());
                     }
@@ -119,19 +322,177 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn read_and_deserialize_manifest(_path: std::path::PathBuf) -> &'static mut Manifest {
-    // TODO Actually parse provided manifest
-
-    let example_manifest = Manifest {
-        secrets: vec![Secret {
-            name: "test".to_string(),
-            source_path: std::path::PathBuf::from(r"/tmp/source"),
-            destination_path: std::path::PathBuf::from(r"/tmp/target"),
-            owner: "bert-proesmans".to_string(),
-            group: "bert-proesmans".to_string(),
-            mode: "0664".to_string(),
-        }],
-    };
+// Names end up in the request path and file names, eg; of credentials, keep them to a conservative
+// character set.
+fn is_valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+// Parses the manifest at the provided path and retains only the secrets targeting the provided
+// guest identity.
+fn read_and_deserialize_manifest(
+    path: std::path::PathBuf,
+    identity: &selector::Identity,
+) -> Result<&'static Manifest, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("failed to read manifest '{}': {}", path.display(), e))?;
+    let mut manifest: Manifest = toml::from_str(&contents)
+        .map_err(|e| format!("failed to parse manifest '{}': {}", path.display(), e))?;
+
+    let mut names = std::collections::HashSet::new();
+    let mut env_files = std::collections::HashMap::new();
+    for secret in manifest.secrets.iter_mut() {
+        if !is_valid_name(&secret.name) {
+            return Err(format!("invalid secret name '{}' in manifest", secret.name).into());
+        }
+        if !names.insert(secret.name.as_str()) {
+            return Err(format!("duplicate secret name '{}' in manifest", secret.name).into());
+        }
 
-    Box::leak(Box::new(example_manifest))
+        match (secret.source_path.take(), &secret.source) {
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "secret '{}' sets both 'source_path' and 'source'",
+                    secret.name
+                )
+                .into());
+            }
+            (Some(path), None) => secret.source = Some(source::Source::from_path(path)),
+            (None, _) => {}
+        }
+
+        if secret.sink.uses_destination_path() != secret.destination_path.is_some() {
+            return Err(format!(
+                "secret '{}' must set 'destination_path' if and only if its sink writes to it",
+                secret.name
+            )
+            .into());
+        }
+
+        if let sink::Sink::Credential(sink::CredentialSink {
+            name: Some(name), ..
+        }) = &secret.sink
+        {
+            if !is_valid_name(name) {
+                return Err(format!(
+                    "secret '{}' has invalid credential name '{}'",
+                    secret.name, name
+                )
+                .into());
+            }
+        }
+
+        if let sink::Sink::EnvFile(env_file) = &secret.sink {
+            let key = env_file.key(&secret.name);
+            if !env_file::is_valid_key(&key) {
+                return Err(format!(
+                    "secret '{}' has invalid environment variable name '{}'",
+                    secret.name, key
+                )
+                .into());
+            }
+            // Secrets sharing a file must agree on its ownership and use distinct variables
+            let (ownership, keys) = env_files.entry(env_file.path.clone()).or_insert_with(|| {
+                (
+                    (
+                        secret.owner.clone(),
+                        secret.group.clone(),
+                        secret.mode.clone(),
+                    ),
+                    std::collections::HashSet::new(),
+                )
+            });
+            if *ownership
+                != (
+                    secret.owner.clone(),
+                    secret.group.clone(),
+                    secret.mode.clone(),
+                )
+            {
+                return Err(format!(
+                    "secret '{}' disagrees on ownership of environment file '{}'",
+                    secret.name,
+                    env_file.path.display()
+                )
+                .into());
+            }
+            if !keys.insert(key.clone()) {
+                return Err(format!(
+                    "environment variable '{}' is set by multiple secrets in '{}'",
+                    key,
+                    env_file.path.display()
+                )
+                .into());
+            }
+        }
+
+        if secret.recipient.is_none() {
+            secret.recipient = manifest.recipient.clone();
+        }
+        if let Some(recipient) = &secret.recipient {
+            encryption::parse_recipient(recipient)
+                .map_err(|e| format!("secret '{}' has {}", secret.name, e))?;
+        }
+
+        if let Some(source::Source::Generate { generator, .. }) = &secret.source {
+            generator
+                .validate()
+                .map_err(|e| format!("secret '{}' has {}", secret.name, e))?;
+        }
+
+        if let Some(source) = &secret.source {
+            if source.is_tree() != secret.is_tree() {
+                return Err(format!(
+                    "secret '{}' must combine a directory or glob source with a destination path ending in '/'",
+                    secret.name
+                )
+                .into());
+            }
+        }
+    }
+
+    manifest.secrets.retain(|secret| match &secret.target {
+        Some(target) => target.matches(identity),
+        None => true,
+    });
+
+    Ok(Box::leak(Box::new(manifest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_manifest(contents: &str) -> Result<&'static Manifest, Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("bss-manifest-{}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let manifest = read_and_deserialize_manifest(path.clone(), &selector::Identity::default());
+        std::fs::remove_file(&path).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn credential_names_stay_in_the_credential_store() {
+        for name in ["..", "../passwd", "a/b", ""] {
+            let manifest = format!(
+                r#"
+                [[secrets]]
+                name = "db"
+                owner = "root"
+                group = "root"
+                mode = "0400"
+                sink = {{ type = "credential", name = "{}" }}
+                "#,
+                name
+            );
+            let error = read_manifest(&manifest).unwrap_err().to_string();
+            assert_eq!(
+                error,
+                format!("secret 'db' has invalid credential name '{}'", name)
+            );
+        }
+    }
 }
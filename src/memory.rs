@@ -0,0 +1,400 @@
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// The amount of bytes read at once into a fresh buffer.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+// Warn about failing to lock memory only once, the limit applies to every allocation after.
+static LOCK_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Prevents the kernel from writing core dumps of this process, and other processes of the same
+// user from attaching to it.
+pub fn disable_core_dumps() {
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        log::warn!(error:% = std::io::Error::last_os_error(); "Failed to disable core dumps");
+    }
+}
+
+// A growable byte buffer for secret material. The memory is locked against swapping, excluded from
+// core dumps and wiped before it's released.
+//
+// NOTE; Locking is best effort, it's limited by RLIMIT_MEMLOCK.
+pub struct SecretBuffer {
+    pointer: NonNull<u8>,
+    capacity: usize,
+    length: usize,
+}
+
+// SAFETY; The buffer exclusively owns its mapping, like a Vec.
+unsafe impl Send for SecretBuffer {}
+unsafe impl Sync for SecretBuffer {}
+
+impl SecretBuffer {
+    pub fn new() -> Self {
+        SecretBuffer {
+            pointer: NonNull::dangling(),
+            capacity: 0,
+            length: 0,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> std::io::Result<Self> {
+        let mut buffer = SecretBuffer::new();
+        buffer.reserve(capacity)?;
+        Ok(buffer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY; The mapping is initialized, anonymous mappings start zeroed
+        unsafe { std::slice::from_raw_parts(self.pointer.as_ptr(), self.length) }
+    }
+
+    // Makes room for at least `additional` more bytes. Fails when the memory can't be mapped, eg;
+    // beyond the limits of the process.
+    pub fn reserve(&mut self, additional: usize) -> std::io::Result<()> {
+        let overflow = || {
+            std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                "secret buffer size overflow",
+            )
+        };
+        let required = self.length.checked_add(additional).ok_or_else(overflow)?;
+        if required <= self.capacity {
+            return Ok(());
+        }
+
+        let capacity = required
+            .max(self.capacity.saturating_mul(2))
+            .checked_next_multiple_of(page_size())
+            .ok_or_else(overflow)?;
+        let pointer = map_locked(capacity)?;
+        let mut grown = SecretBuffer {
+            pointer,
+            capacity,
+            length: 0,
+        };
+        grown.extend_from_slice(self.as_slice())?;
+        // The old mapping is wiped when it's dropped
+        *self = grown;
+        Ok(())
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.reserve(data.len())?;
+        self.spare_capacity()[..data.len()].copy_from_slice(data);
+        self.length += data.len();
+        Ok(())
+    }
+
+    // The unused part of the buffer, bytes written to it are claimed by raising the length.
+    fn spare_capacity(&mut self) -> &mut [u8] {
+        // SAFETY; Everything up to the capacity is mapped and initialized
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.pointer.as_ptr().add(self.length),
+                self.capacity - self.length,
+            )
+        }
+    }
+
+    // Appends the bytes of a single read, zero at the end of the reader.
+    pub async fn read_chunk(
+        &mut self,
+        reader: &mut (impl tokio::io::AsyncRead + Unpin),
+    ) -> std::io::Result<usize> {
+        use tokio::io::AsyncReadExt;
+
+        if self.length == self.capacity {
+            self.reserve(CHUNK_SIZE)?;
+        }
+        let read = reader.read(self.spare_capacity()).await?;
+        self.length += read;
+        Ok(read)
+    }
+
+    // Appends everything the reader produces. A buffer sized to the content up front is only grown
+    // if the reader produces more.
+    pub async fn read_from(
+        &mut self,
+        reader: &mut (impl tokio::io::AsyncRead + Unpin),
+    ) -> std::io::Result<usize> {
+        use tokio::io::AsyncReadExt;
+
+        let start = self.length;
+        loop {
+            if self.length < self.capacity {
+                if self.read_chunk(reader).await? == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            // One byte tells the end of the reader apart, without growing a full buffer
+            let mut probe = zeroize::Zeroizing::new([0u8; 1]);
+            if reader.read(probe.as_mut_slice()).await? == 0 {
+                break;
+            }
+            self.extend_from_slice(probe.as_slice())?;
+        }
+        Ok(self.length - start)
+    }
+
+    // Appends exactly `length` bytes of the reader.
+    pub async fn read_exact_from(
+        &mut self,
+        reader: &mut (impl tokio::io::AsyncRead + Unpin),
+        length: usize,
+    ) -> std::io::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        self.reserve(length)?;
+        reader
+            .read_exact(&mut self.spare_capacity()[..length])
+            .await?;
+        self.length += length;
+        Ok(())
+    }
+
+    // Appends everything the reader produces, see [SecretBuffer::read_from].
+    pub fn read_from_blocking(
+        &mut self,
+        reader: &mut impl std::io::Read,
+    ) -> std::io::Result<usize> {
+        let start = self.length;
+        loop {
+            if self.length == self.capacity {
+                self.reserve(CHUNK_SIZE)?;
+            }
+            match reader.read(self.spare_capacity()) {
+                Ok(0) => return Ok(self.length - start),
+                Ok(read) => self.length += read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Wipes the content, the capacity is kept.
+    pub fn clear(&mut self) {
+        use zeroize::Zeroize;
+
+        self.allocation().zeroize();
+        self.length = 0;
+    }
+
+    fn allocation(&mut self) -> &mut [u8] {
+        // SAFETY; Everything up to the capacity is mapped and initialized
+        unsafe { std::slice::from_raw_parts_mut(self.pointer.as_ptr(), self.capacity) }
+    }
+
+    // Moves the bytes into a buffer that wipes them when the last reference is dropped.
+    pub fn into_bytes(self) -> bytes::Bytes {
+        bytes::Bytes::from_owner(self)
+    }
+}
+
+impl Default for SecretBuffer {
+    fn default() -> Self {
+        SecretBuffer::new()
+    }
+}
+
+// Takes over the content and wipes the original allocation.
+//
+// NOTE; Copies left behind by earlier reallocations of the vector are out of reach.
+impl TryFrom<Vec<u8>> for SecretBuffer {
+    type Error = std::io::Error;
+
+    fn try_from(mut data: Vec<u8>) -> std::io::Result<Self> {
+        use zeroize::Zeroize;
+
+        let buffer = SecretBuffer::try_from(data.as_slice());
+        data.zeroize();
+        buffer
+    }
+}
+
+impl TryFrom<&[u8]> for SecretBuffer {
+    type Error = std::io::Error;
+
+    fn try_from(data: &[u8]) -> std::io::Result<Self> {
+        let mut buffer = SecretBuffer::with_capacity(data.len())?;
+        buffer.extend_from_slice(data)?;
+        Ok(buffer)
+    }
+}
+
+impl std::ops::Deref for SecretBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for SecretBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+// WARN; Never print the content!
+impl std::fmt::Debug for SecretBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretBuffer({} bytes)", self.length)
+    }
+}
+
+impl Drop for SecretBuffer {
+    fn drop(&mut self) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.clear();
+        // SAFETY; The mapping was created by map_locked with this capacity
+        unsafe {
+            libc::munlock(self.pointer.as_ptr().cast(), self.capacity);
+            libc::munmap(self.pointer.as_ptr().cast(), self.capacity);
+        }
+    }
+}
+
+// Buffers a reader in locked memory, the counterpart of [tokio::io::BufReader].
+pub struct SecretBufReader<R> {
+    inner: R,
+    buffer: SecretBuffer,
+    position: usize,
+}
+
+impl<R> SecretBufReader<R> {
+    pub fn new(inner: R) -> std::io::Result<Self> {
+        Ok(SecretBufReader {
+            inner,
+            buffer: SecretBuffer::with_capacity(CHUNK_SIZE)?,
+            position: 0,
+        })
+    }
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncBufRead for SecretBufReader<R> {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.position >= this.buffer.len() {
+            this.buffer.clear();
+            this.position = 0;
+            let mut read_buffer = tokio::io::ReadBuf::new(this.buffer.spare_capacity());
+            std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, &mut read_buffer))?;
+            let read = read_buffer.filled().len();
+            this.buffer.length += read;
+        }
+
+        std::task::Poll::Ready(Ok(&this.buffer.as_slice()[this.position..]))
+    }
+
+    fn consume(self: std::pin::Pin<&mut Self>, amount: usize) {
+        let this = self.get_mut();
+        this.position = (this.position + amount).min(this.buffer.len());
+    }
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for SecretBufReader<R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        output: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        use tokio::io::AsyncBufRead;
+
+        let available = std::task::ready!(self.as_mut().poll_fill_buf(cx))?;
+        let amount = available.len().min(output.remaining());
+        output.put_slice(&available[..amount]);
+        self.consume(amount);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+// Maps zeroed anonymous memory, locked into RAM and excluded from core dumps.
+fn map_locked(capacity: usize) -> std::io::Result<NonNull<u8>> {
+    let pointer = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            capacity,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if pointer == libc::MAP_FAILED {
+        let e = std::io::Error::last_os_error();
+        return Err(std::io::Error::new(
+            e.kind(),
+            format!("failed to map {} bytes for secrets: {}", capacity, e),
+        ));
+    }
+
+    unsafe {
+        libc::madvise(pointer, capacity, libc::MADV_DONTDUMP);
+    }
+    if unsafe { libc::mlock(pointer, capacity) } != 0 && !LOCK_WARNED.swap(true, Ordering::Relaxed)
+    {
+        log::warn!(
+            error:% = std::io::Error::last_os_error();
+            "Failed to lock memory for secrets, they may be swapped to disk"
+        );
+    }
+
+    NonNull::new(pointer.cast())
+        .ok_or_else(|| std::io::Error::other("mmap returned a null mapping"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_to_map_is_an_error() {
+        let mut buffer = SecretBuffer::try_from(&b"secret"[..]).unwrap();
+        assert!(buffer.reserve(usize::MAX).is_err());
+        assert!(SecretBuffer::with_capacity(usize::MAX / 2).is_err());
+        assert_eq!(buffer.as_slice(), b"secret");
+    }
+
+    #[test]
+    fn growing_keeps_the_content() {
+        let mut buffer = SecretBuffer::new();
+        buffer.extend_from_slice(b"a").unwrap();
+        buffer.extend_from_slice(&[b'b'; CHUNK_SIZE]).unwrap();
+        assert_eq!(buffer.len(), CHUNK_SIZE + 1);
+        assert_eq!(buffer[0], b'a');
+        assert!(buffer[1..].iter().all(|&byte| byte == b'b'));
+    }
+
+    #[tokio::test]
+    async fn reading_into_a_sized_buffer_keeps_its_capacity() {
+        let content = vec![b'c'; page_size()];
+        let mut buffer = SecretBuffer::with_capacity(content.len()).unwrap();
+        buffer.read_from(&mut content.as_slice()).await.unwrap();
+        assert_eq!(buffer.as_slice(), content.as_slice());
+        assert_eq!(buffer.capacity, content.len());
+
+        // A reader producing more than expected still grows it
+        buffer.read_from(&mut &b"more"[..]).await.unwrap();
+        assert_eq!(buffer.len(), content.len() + 4);
+    }
+}
@@ -0,0 +1,216 @@
+// Counters of the receiver, served in the Prometheus text format on a listener of their own, see
+// [serve]. Uploads are labelled by the name of their secret in the manifest, never by anything the
+// peer controls or by content.
+//
+// eg;
+// bss_uploads_total{secret="db",outcome="delivered"} 1
+// bss_received_bytes_total{secret="db"} 24
+// bss_upload_duration_seconds_bucket{secret="db",le="0.005"} 1
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Upper bounds of the latency histogram, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // Cumulative counts per bucket of [DURATION_BUCKETS].
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct SecretMetrics {
+    // By outcome, see [super::receive::describe_outcome].
+    uploads: BTreeMap<&'static str, u64>,
+    received_bytes: u64,
+    duration: Histogram,
+}
+
+pub struct Metrics {
+    // NOTE; Only names of the manifest are keys, the amount of series is bounded by it
+    secrets: Mutex<BTreeMap<String, SecretMetrics>>,
+    // Connections dropped before a request, eg; failing the TLS handshake.
+    rejected_peers: AtomicU64,
+    // Requests refused for a missing or invalid token or signature.
+    auth_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(manifest: &super::Manifest) -> Self {
+        let secrets = manifest
+            .secrets
+            .iter()
+            .map(|secret| {
+                let metrics = SecretMetrics {
+                    duration: Histogram {
+                        buckets: vec![0; DURATION_BUCKETS.len()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                (secret.name.clone(), metrics)
+            })
+            .collect();
+
+        Metrics {
+            secrets: Mutex::new(secrets),
+            rejected_peers: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+        }
+    }
+
+    // Records a finished upload. Uploads addressing no secret of the manifest aren't recorded.
+    pub fn record_upload(
+        &self,
+        secret: &str,
+        outcome: &'static str,
+        bytes: u64,
+        duration: std::time::Duration,
+    ) {
+        let mut secrets = self.secrets.lock().expect("metrics lock poisoned");
+        let Some(metrics) = secrets.get_mut(secret) else {
+            return;
+        };
+
+        *metrics.uploads.entry(outcome).or_default() += 1;
+        metrics.received_bytes += bytes;
+        let seconds = duration.as_secs_f64();
+        let histogram = &mut metrics.duration;
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    pub fn record_rejected_peer(&self) {
+        self.rejected_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Renders the text exposition format.
+    //
+    // REF; https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render(&self) -> String {
+        let secrets = self.secrets.lock().expect("metrics lock poisoned");
+        let mut text = String::new();
+
+        // NOTE; Secret names are limited to characters that need no escaping in label values
+        let _ = writeln!(
+            text,
+            "# HELP bss_uploads_total Uploads by secret and outcome."
+        );
+        let _ = writeln!(text, "# TYPE bss_uploads_total counter");
+        for (name, metrics) in secrets.iter() {
+            for (outcome, count) in metrics.uploads.iter() {
+                let _ = writeln!(
+                    text,
+                    "bss_uploads_total{{secret=\"{}\",outcome=\"{}\"}} {}",
+                    name, outcome, count
+                );
+            }
+        }
+
+        let _ = writeln!(
+            text,
+            "# HELP bss_received_bytes_total Bytes of upload bodies received by secret."
+        );
+        let _ = writeln!(text, "# TYPE bss_received_bytes_total counter");
+        for (name, metrics) in secrets.iter() {
+            let _ = writeln!(
+                text,
+                "bss_received_bytes_total{{secret=\"{}\"}} {}",
+                name, metrics.received_bytes
+            );
+        }
+
+        let _ = writeln!(
+            text,
+            "# HELP bss_upload_duration_seconds Time from receiving the request until the upload is delivered or failed."
+        );
+        let _ = writeln!(text, "# TYPE bss_upload_duration_seconds histogram");
+        for (name, metrics) in secrets.iter() {
+            let histogram = &metrics.duration;
+            for (bucket, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+                let _ = writeln!(
+                    text,
+                    "bss_upload_duration_seconds_bucket{{secret=\"{}\",le=\"{}\"}} {}",
+                    name, bound, bucket
+                );
+            }
+            let _ = writeln!(
+                text,
+                "bss_upload_duration_seconds_bucket{{secret=\"{}\",le=\"+Inf\"}} {}",
+                name, histogram.count
+            );
+            let _ = writeln!(
+                text,
+                "bss_upload_duration_seconds_sum{{secret=\"{}\"}} {}",
+                name, histogram.sum
+            );
+            let _ = writeln!(
+                text,
+                "bss_upload_duration_seconds_count{{secret=\"{}\"}} {}",
+                name, histogram.count
+            );
+        }
+
+        let _ = writeln!(
+            text,
+            "# HELP bss_rejected_peers_total Connections dropped before a request, eg; failing the TLS handshake."
+        );
+        let _ = writeln!(text, "# TYPE bss_rejected_peers_total counter");
+        let _ = writeln!(
+            text,
+            "bss_rejected_peers_total {}",
+            self.rejected_peers.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            text,
+            "# HELP bss_auth_failures_total Requests refused for a missing or invalid token or signature."
+        );
+        let _ = writeln!(text, "# TYPE bss_auth_failures_total counter");
+        let _ = writeln!(
+            text,
+            "bss_auth_failures_total {}",
+            self.auth_failures.load(Ordering::Relaxed)
+        );
+
+        text
+    }
+}
+
+// Serves GET /metrics on the listener until the runtime stops. The listener is bound before
+// splitting off the network worker, which serves it.
+//
+// NOTE; Metrics require no authorization, bind the listener to an address only the scraper reaches
+pub fn serve(listener: std::net::TcpListener, metrics: &'static Metrics) -> std::io::Result<()> {
+    use warp::Filter;
+
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let route = warp::get().and(warp::path!("metrics")).map(move || {
+        warp::reply::with_header(
+            metrics.render(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+    });
+
+    tokio::spawn(
+        warp::serve(route).run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+    );
+    Ok(())
+}
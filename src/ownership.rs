@@ -0,0 +1,94 @@
+use std::ffi::CString;
+
+// Ownership and permissions to apply to a delivered secret, resolved from the manifest.
+#[derive(Debug, Clone, Copy)]
+pub struct Ownership {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+}
+
+impl Ownership {
+    pub fn resolve(owner: &str, group: &str, mode: &str) -> Result<Self, String> {
+        Ok(Ownership {
+            uid: lookup_user(owner)?,
+            gid: lookup_group(group)?,
+            mode: parse_mode(mode)?,
+        })
+    }
+}
+
+// Parses an octal permission string like "0640" or "640".
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(value) if value <= 0o7777 => Ok(value),
+        _ => Err(format!("invalid mode '{}'", mode)),
+    }
+}
+
+// Resolves a user name or numeric user id.
+pub fn lookup_user(owner: &str) -> Result<u32, String> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+
+    lookup_account(owner).map(|(uid, _)| uid)
+}
+
+// Resolves a user name into its user id and primary group id.
+pub fn lookup_account(owner: &str) -> Result<(u32, u32), String> {
+    let name = CString::new(owner).map_err(|_| format!("invalid user '{}'", owner))?;
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    match (status, result.is_null()) {
+        (0, false) => Ok((entry.pw_uid, entry.pw_gid)),
+        (0, true) => Err(format!("unknown user '{}'", owner)),
+        (errno, _) => Err(format!(
+            "failed to lookup user '{}': {}",
+            owner,
+            std::io::Error::from_raw_os_error(errno)
+        )),
+    }
+}
+
+// Resolves a group name or numeric group id.
+pub fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(|_| format!("invalid group '{}'", group))?;
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    match (status, result.is_null()) {
+        (0, false) => Ok(entry.gr_gid),
+        (0, true) => Err(format!("unknown group '{}'", group)),
+        (errno, _) => Err(format!(
+            "failed to lookup group '{}': {}",
+            group,
+            std::io::Error::from_raw_os_error(errno)
+        )),
+    }
+}
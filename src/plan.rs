@@ -0,0 +1,61 @@
+// The outcome of a dry run, printed as JSON on standard output. Each secret lists the problems that
+// would fail it, the dry run fails when there are any.
+//
+// eg;
+// {"side":"send","secrets":[{"secret":"db","source":"file","destination":"/etc/db.conf","size":24,"digest":"9f86..","encrypted":false,"body_size":24,"problems":[]}]}
+
+#[derive(serde::Serialize, Debug)]
+pub struct Entry<T> {
+    #[serde(flatten)]
+    pub detail: T,
+    pub problems: Vec<String>,
+}
+
+// A transfer the sender would make.
+#[derive(serde::Serialize, Debug)]
+pub struct Transfer {
+    pub secret: String,
+    pub source: &'static str,
+    pub destination: String,
+    // Size and SHA-256 digest of the plaintext. The digest is unset for generated content that
+    // isn't persisted yet, it differs on every run.
+    pub size: Option<u64>,
+    pub digest: Option<String>,
+    pub encrypted: bool,
+    // Size of the request body, checked against --bytes-max.
+    pub body_size: Option<u64>,
+}
+
+// A delivery the receiver would make.
+#[derive(serde::Serialize, Debug)]
+pub struct Delivery {
+    pub secret: String,
+    pub destination: String,
+    pub owner: String,
+    pub group: String,
+    pub mode: String,
+    // Resolved on this machine, which may differ from the guest.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub encrypted: bool,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Plan<'a, T> {
+    side: super::audit::Side,
+    secrets: &'a [Entry<T>],
+}
+
+// Prints the plan and fails when any secret has problems.
+pub fn print<T: serde::Serialize>(
+    side: super::audit::Side,
+    secrets: &[Entry<T>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(&Plan { side, secrets })?);
+
+    let problems: usize = secrets.iter().map(|entry| entry.problems.len()).sum();
+    if problems > 0 {
+        return Err(format!("dry run found {} problem(s)", problems).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,595 @@
+// The receiver splits into two processes when started as root. The network worker switches to an
+// unprivileged account and serves the peer, the privileged writer stays root and delivers what the
+// worker hands over. The writer resolves destinations, ownership and sinks from its own manifest,
+// the worker only provides the request path and the body of an upload.
+//
+// Both sides exchange frames of [kind: u8][id: u32 BE][length: u32 BE][payload] over a socketpair.
+use super::memory::SecretBuffer;
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+// Commands of the worker.
+const CREATE: u8 = 1; // Starts an upload, the payload is a [Create]
+const WRITE: u8 = 2; // Appends the payload to the body of the upload
+const COMMIT: u8 = 3; // Ends the body and delivers it, answered with the outcome
+const ABORT: u8 = 4; // Discards the upload, the payload is the reason
+const COMPLETE: u8 = 5; // Completes the session, answered with the report
+const STATUS: u8 = 6; // Answered with the report of the session
+
+// Answers of the writer, the id is the id of the command.
+const ANSWER: u8 = 128;
+
+const HEADER_LENGTH: usize = 9;
+// The writer refuses larger commands, bodies are written in chunks.
+const MAX_COMMAND_LENGTH: usize = 64 * 1024;
+// Reports grow with the amount of secrets in the manifest.
+const MAX_ANSWER_LENGTH: usize = 16 * 1024 * 1024;
+// Frames queued towards the socket, and body chunks queued towards a single delivery.
+const BACKLOG: usize = 16;
+
+// Payload of the command starting an upload.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Create {
+    // The request path below /secrets/.
+    tail: String,
+    // The peer of the connection, for the audit log.
+    //
+    // NOTE; Reported by the worker, the writer has no way to verify it
+    peer: String,
+    // The writer decrypts encrypted bodies.
+    body: super::receive::Body,
+}
+
+// The process [split] returns in.
+pub enum Role {
+    // Serves the peer without privileges, over the socket to the writer.
+    Worker(UnixStream),
+    // Delivers uploads of the worker with the provided pid.
+    Writer(UnixStream, libc::pid_t),
+}
+
+// Forks the network worker, which switches to the provided account before returning.
+//
+// WARN; Must be called before any threads are started, only the calling thread survives the fork!
+pub fn split(uid: u32, gid: u32) -> std::io::Result<Role> {
+    let (worker, writer) = UnixStream::pair()?;
+    let parent = unsafe { libc::getpid() };
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => {
+            drop(writer);
+            drop_privileges(uid, gid)?;
+            // The worker is useless without the writer, the signal is set after changing
+            // credentials because that clears it
+            if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if unsafe { libc::getppid() } != parent {
+                return Err(std::io::Error::other("the privileged writer exited"));
+            }
+            Ok(Role::Worker(worker))
+        }
+        pid => {
+            drop(worker);
+            Ok(Role::Writer(writer, pid))
+        }
+    }
+}
+
+// Switches every user and group id of this process to the account, for good.
+fn drop_privileges(uid: u32, gid: u32) -> std::io::Result<()> {
+    // NOTE; Changing the bounding set requires privileges this process is about to lose
+    super::sandbox::limit_bounding_set(&Default::default())?;
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) != 0
+            || libc::setresgid(gid, gid, gid) != 0
+            || libc::setresuid(uid, uid, uid) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    // Regaining root would defeat the separation
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(std::io::Error::other(
+            "the worker can regain root privileges",
+        ));
+    }
+    super::sandbox::restrict_capabilities(&Default::default())?;
+    super::sandbox::set_no_new_privs()?;
+    // NOTE; Changing credentials resets the flag to the system default
+    super::memory::disable_core_dumps();
+
+    Ok(())
+}
+
+// Waits for the worker to exit and returns its exit code.
+pub fn wait(worker: libc::pid_t) -> std::io::Result<i32> {
+    let mut status = 0;
+    while unsafe { libc::waitpid(worker, &mut status, 0) } == -1 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    if libc::WIFEXITED(status) {
+        Ok(libc::WEXITSTATUS(status))
+    } else if libc::WIFSIGNALED(status) {
+        Ok(128 + libc::WTERMSIG(status))
+    } else {
+        Ok(1)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    payload: SecretBuffer,
+}
+
+// Reads the next frame, none when the other side closed the socket.
+async fn read_frame(
+    reader: &mut (impl tokio::io::AsyncRead + Unpin),
+    max_length: usize,
+) -> std::io::Result<Option<Frame>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0u8; HEADER_LENGTH];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let id = u32::from_be_bytes(header[1..5].try_into().expect("4 byte id"));
+    let length = u32::from_be_bytes(header[5..9].try_into().expect("4 byte length")) as usize;
+    if length > max_length {
+        return Err(protocol_error(format!(
+            "frame of {} bytes exceeds the limit of {}",
+            length, max_length
+        )));
+    }
+
+    let mut payload = SecretBuffer::with_capacity(length)?;
+    payload.read_exact_from(reader, length).await?;
+    Ok(Some(Frame {
+        kind: header[0],
+        id,
+        payload,
+    }))
+}
+
+fn protocol_error(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+fn disconnected() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "the other side of the privilege separation is gone",
+    )
+}
+
+// Queues whole frames for a task owning the socket, so a cancelled request never leaves a partial
+// frame behind.
+#[derive(Clone)]
+struct Outbox(mpsc::Sender<SecretBuffer>);
+
+impl Outbox {
+    fn spawn(mut socket: tokio::net::unix::OwnedWriteHalf) -> Self {
+        use tokio::io::AsyncWriteExt;
+
+        let (sender, mut receiver) = mpsc::channel::<SecretBuffer>(BACKLOG);
+        tokio::spawn(async move {
+            while let Some(frame) = receiver.recv().await {
+                if let Err(e) = socket.write_all(&frame).await {
+                    log::error!(error:% = e; "Failed writing to the privilege separation socket");
+                    break;
+                }
+            }
+        });
+        Outbox(sender)
+    }
+
+    async fn send(&self, kind: u8, id: u32, payload: &[u8]) -> std::io::Result<()> {
+        let length = u32::try_from(payload.len()).map_err(|_| protocol_error("frame too large"))?;
+        let mut frame = SecretBuffer::with_capacity(HEADER_LENGTH + payload.len())?;
+        frame.extend_from_slice(&[kind])?;
+        frame.extend_from_slice(&id.to_be_bytes())?;
+        frame.extend_from_slice(&length.to_be_bytes())?;
+        frame.extend_from_slice(payload)?;
+        self.0.send(frame).await.map_err(|_| disconnected())
+    }
+}
+
+// Requests of the worker waiting for an answer, gone after the writer closed the socket.
+type Answers = Arc<Mutex<Option<HashMap<u32, oneshot::Sender<SecretBuffer>>>>>;
+
+// The side of the network worker, hands uploads over to the privileged writer.
+#[derive(Clone)]
+pub struct WriterClient {
+    outbox: Outbox,
+    answers: Answers,
+    next_id: Arc<AtomicU32>,
+}
+
+impl WriterClient {
+    // Must be created within the runtime, answers are dispatched by a background task.
+    pub fn new(socket: UnixStream) -> std::io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        let (mut reader, writer) = tokio::net::UnixStream::from_std(socket)?.into_split();
+        let answers: Answers = Arc::new(Mutex::new(Some(HashMap::new())));
+
+        let pending = answers.clone();
+        tokio::spawn(async move {
+            loop {
+                match read_frame(&mut reader, MAX_ANSWER_LENGTH).await {
+                    Ok(Some(frame)) if frame.kind == ANSWER => {
+                        let sender = pending
+                            .lock()
+                            .expect("answers lock poisoned")
+                            .as_mut()
+                            .and_then(|answers| answers.remove(&frame.id));
+                        if let Some(sender) = sender {
+                            let _ = sender.send(frame.payload);
+                        }
+                    }
+                    Ok(Some(frame)) => {
+                        log::error!("Unexpected frame {} from the privileged writer", frame.kind);
+                        break;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::error!(error:% = e; "Failed reading from the privileged writer");
+                        break;
+                    }
+                }
+            }
+            // Fails every request waiting for an answer, and every request after
+            pending.lock().expect("answers lock poisoned").take();
+        });
+
+        Ok(WriterClient {
+            outbox: Outbox::spawn(writer),
+            answers,
+            next_id: Arc::new(AtomicU32::new(0)),
+        })
+    }
+
+    fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Sends the command and returns the receiver of its answer.
+    async fn request(
+        &self,
+        kind: u8,
+        id: u32,
+        payload: &[u8],
+    ) -> std::io::Result<oneshot::Receiver<SecretBuffer>> {
+        let (sender, receiver) = oneshot::channel();
+        match self.answers.lock().expect("answers lock poisoned").as_mut() {
+            Some(answers) => answers.insert(id, sender),
+            None => return Err(disconnected()),
+        };
+        self.outbox.send(kind, id, payload).await?;
+        Ok(receiver)
+    }
+
+    // Starts handing over an upload, addressed by the request path below /secrets/.
+    pub async fn create(
+        &self,
+        tail: &str,
+        peer: &str,
+        body: super::receive::Body,
+    ) -> std::io::Result<Upload> {
+        let id = self.next_id();
+        let create = Create {
+            tail: tail.to_string(),
+            peer: peer.to_string(),
+            body,
+        };
+        self.outbox
+            .send(CREATE, id, &serde_json::to_vec(&create)?)
+            .await?;
+        Ok(Upload {
+            client: self.clone(),
+            id,
+            open: true,
+        })
+    }
+
+    // Completes the session, which runs the post-delivery hooks.
+    pub async fn complete(
+        &self,
+        completion: &super::session::Completion,
+    ) -> std::io::Result<super::session::Report> {
+        let completion = serde_json::to_vec(completion)?;
+        let answer = self.request(COMPLETE, self.next_id(), &completion).await?;
+        let answer = answer.await.map_err(|_| disconnected())?;
+        Ok(serde_json::from_slice(&answer)?)
+    }
+
+    pub async fn report(&self) -> std::io::Result<super::session::Report> {
+        let answer = self.request(STATUS, self.next_id(), &[]).await?;
+        let answer = answer.await.map_err(|_| disconnected())?;
+        Ok(serde_json::from_slice(&answer)?)
+    }
+}
+
+// An upload handed over to the privileged writer. Dropping it without committing discards it.
+pub struct Upload {
+    client: WriterClient,
+    id: u32,
+    open: bool,
+}
+
+impl Upload {
+    // Streams the body to the writer in locked chunks.
+    pub async fn write_from(
+        &mut self,
+        body: &mut (impl tokio::io::AsyncRead + Unpin),
+    ) -> std::io::Result<()> {
+        let mut chunk = SecretBuffer::with_capacity(super::memory::CHUNK_SIZE)?;
+        loop {
+            chunk.clear();
+            if chunk.read_chunk(body).await? == 0 {
+                return Ok(());
+            }
+            self.client.outbox.send(WRITE, self.id, &chunk).await?;
+        }
+    }
+
+    // Delivers the body and returns the outcome.
+    pub async fn commit(mut self) -> std::io::Result<super::receive::Outcome> {
+        let answer = self.client.request(COMMIT, self.id, &[]).await?;
+        self.open = false;
+        let answer = answer.await.map_err(|_| disconnected())?;
+        Ok(serde_json::from_slice(&answer)?)
+    }
+
+    // Discards the body, the reason is recorded as failure of the secret.
+    pub async fn abort(mut self, reason: &str) {
+        self.open = false;
+        if let Err(e) = self
+            .client
+            .outbox
+            .send(ABORT, self.id, reason.as_bytes())
+            .await
+        {
+            log::error!(error:% = e; "Failed aborting upload");
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+
+        // The request was cancelled, eg; the peer disconnected
+        let outbox = self.client.outbox.clone();
+        let id = self.id;
+        tokio::spawn(async move {
+            let _ = outbox.send(ABORT, id, b"upload cancelled").await;
+        });
+    }
+}
+
+// An upload in progress on the writer side.
+struct Delivery {
+    secret: &'static super::Secret,
+    // The path of the upload below /secrets, directory entries are recorded one by one.
+    tail: String,
+    peer: String,
+    destination: String,
+    body: mpsc::Sender<std::io::Result<bytes::Bytes>>,
+    task: tokio::task::JoinHandle<(Result<(), warp::reject::Rejection>, super::audit::Content)>,
+}
+
+// Runs the privileged writer until the worker closes its socket.
+#[tokio::main(flavor = "current_thread")]
+pub async fn run_writer(
+    manifest: &'static super::Manifest,
+    socket: UnixStream,
+    identities: Vec<age::x25519::Identity>,
+    reporting: super::receive::Reporting,
+) -> Result<(), Box<dyn std::error::Error>> {
+    socket.set_nonblocking(true)?;
+    let (mut reader, writer) = tokio::net::UnixStream::from_std(socket)?.into_split();
+    let outbox = Outbox::spawn(writer);
+    let state = Arc::new(super::StateType::new(manifest, identities, None, reporting));
+    // Uploads of the worker, none for request paths that address nothing
+    let mut deliveries: HashMap<u32, Option<Delivery>> = HashMap::new();
+
+    let result = loop {
+        let frame = match read_frame(&mut reader, MAX_COMMAND_LENGTH).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+
+        match frame.kind {
+            CREATE => {
+                if deliveries.contains_key(&frame.id) {
+                    break Err(protocol_error("upload id reused"));
+                }
+                let create: Create = match serde_json::from_slice(&frame.payload) {
+                    Ok(create) => create,
+                    Err(e) => break Err(e.into()),
+                };
+                let delivery = super::receive::resolve_destination(manifest, &create.tail).map(
+                    |(secret, target_file_path)| start(secret, target_file_path, create, &state),
+                );
+                deliveries.insert(frame.id, delivery);
+            }
+            WRITE => match deliveries.get(&frame.id) {
+                Some(Some(delivery)) => {
+                    // NOTE; A failed delivery stops reading, its outcome is answered on commit
+                    let _ = delivery.body.send(Ok(frame.payload.into_bytes())).await;
+                }
+                Some(None) => {}
+                None => break Err(protocol_error("write to an unknown upload")),
+            },
+            COMMIT => {
+                let Some(delivery) = deliveries.remove(&frame.id) else {
+                    break Err(protocol_error("commit of an unknown upload"));
+                };
+                let (outbox, state) = (outbox.clone(), state.clone());
+                tokio::spawn(async move {
+                    let outcome = match delivery {
+                        Some(delivery) => finish(delivery, None, &state).await,
+                        None => super::receive::Outcome::NotFound,
+                    };
+                    answer(&outbox, frame.id, &outcome).await;
+                });
+            }
+            ABORT => {
+                let Some(delivery) = deliveries.remove(&frame.id) else {
+                    break Err(protocol_error("abort of an unknown upload"));
+                };
+                let reason = String::from_utf8_lossy(&frame.payload).into_owned();
+                if let Some(delivery) = delivery {
+                    let state = state.clone();
+                    tokio::spawn(async move { finish(delivery, Some(reason), &state).await });
+                }
+            }
+            COMPLETE => {
+                let Ok(completion) = serde_json::from_slice(&frame.payload) else {
+                    break Err(protocol_error("malformed completion"));
+                };
+                let (outbox, state) = (outbox.clone(), state.clone());
+                tokio::spawn(async move {
+                    let report = state.complete(manifest, &completion).await;
+                    answer(&outbox, frame.id, &report).await;
+                });
+            }
+            STATUS => {
+                let report = state
+                    .session
+                    .lock()
+                    .expect("session lock poisoned")
+                    .report();
+                answer(&outbox, frame.id, &report).await;
+            }
+            kind => break Err(protocol_error(format!("unknown command {}", kind))),
+        }
+    };
+
+    // A body cut short is never delivered
+    for delivery in deliveries.into_values().flatten() {
+        finish(delivery, Some("the network worker stopped".into()), &state).await;
+    }
+
+    result.map_err(|e| format!("privileged writer failed: {}", e).into())
+}
+
+// Starts decrypting and delivering a body that arrives over a channel.
+fn start(
+    secret: &'static super::Secret,
+    target_file_path: Option<std::path::PathBuf>,
+    create: Create,
+    state: &Arc<super::StateType>,
+) -> Delivery {
+    let Create {
+        tail,
+        peer,
+        body: announced,
+    } = create;
+    let destination = secret
+        .sink
+        .describe_destination(&secret.name, target_file_path.as_deref());
+    let (body, chunks) = mpsc::channel(BACKLOG);
+    let state = state.clone();
+    let task = tokio::spawn(async move {
+        let mut chunks =
+            tokio_util::io::StreamReader::new(tokio_stream::wrappers::ReceiverStream::new(chunks));
+        let mut content = super::audit::Content::default();
+        let result = super::receive::decrypt_and_deliver(
+            secret,
+            target_file_path,
+            announced,
+            &mut chunks,
+            &mut content,
+            &state,
+        )
+        .await;
+        (result, content)
+    });
+
+    Delivery {
+        secret,
+        tail,
+        peer,
+        destination,
+        body,
+        task,
+    }
+}
+
+// Ends the body and records the outcome of the delivery. Aborting fails the body, so the sink
+// discards what it received.
+async fn finish(
+    delivery: Delivery,
+    abort: Option<String>,
+    state: &super::StateType,
+) -> super::receive::Outcome {
+    let Delivery {
+        secret,
+        tail,
+        peer,
+        destination,
+        body,
+        task,
+    } = delivery;
+    if let Some(reason) = &abort {
+        let _ = body.send(Err(std::io::Error::other(reason.clone()))).await;
+    }
+    drop(body);
+
+    let (outcome, content) = match task.await {
+        Ok((result, content)) => (super::receive::Outcome::from(&result), content),
+        Err(e) => {
+            log::error!(secret = secret.name.as_str(), error:% = e; "Delivery failed");
+            (
+                super::receive::Outcome::WriteIoFail,
+                super::audit::Content::default(),
+            )
+        }
+    };
+    let result = outcome.to_result();
+    state.audit(super::audit::Transfer {
+        peer,
+        secret: secret.name.clone(),
+        digest: (abort.is_none() && result.is_ok()).then(|| content.digest()),
+        size: content.size(),
+        destination,
+        outcome: match (&abort, &result) {
+            (Some(_), _) => "aborted",
+            (None, Ok(())) => "delivered",
+            (None, Err(rejection)) => super::receive::describe_outcome(rejection),
+        }
+        .to_string(),
+    });
+    let recorded = match abort {
+        Some(reason) => Err(reason),
+        None => result.map_err(|e| format!("{:?}", e)),
+    };
+    state.record_delivery(&secret.name, &tail, recorded);
+
+    outcome
+}
+
+async fn answer(outbox: &Outbox, id: u32, value: &impl serde::Serialize) {
+    let result = match serde_json::to_vec(value) {
+        Ok(payload) => outbox.send(ANSWER, id, &payload).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        log::error!(error:% = e; "Failed answering the network worker");
+    }
+}
@@ -6,6 +6,238 @@ impl warp::reject::Reject for CreateIOFail {}
 struct WriteIOFail;
 impl warp::reject::Reject for WriteIOFail {}
 
+#[derive(Debug)]
+struct OwnershipFail;
+impl warp::reject::Reject for OwnershipFail {}
+
+#[derive(Debug)]
+struct SinkFail;
+impl warp::reject::Reject for SinkFail {}
+
+#[derive(Debug)]
+struct InvalidPayload;
+impl warp::reject::Reject for InvalidPayload {}
+
+#[derive(Debug)]
+struct CommandFail {
+    timeout: bool,
+    message: String,
+}
+impl warp::reject::Reject for CommandFail {}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+struct NonceUnavailable;
+impl warp::reject::Reject for NonceUnavailable {}
+
+#[derive(Debug)]
+struct EncryptionRequired;
+impl warp::reject::Reject for EncryptionRequired {}
+
+// The outcome of a delivery by the privileged writer, see [super::privsep].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Delivered,
+    NotFound,
+    CreateIoFail,
+    WriteIoFail,
+    OwnershipFail,
+    SinkFail,
+    InvalidPayload,
+    EncryptionRequired,
+    CommandFail { timeout: bool, message: String },
+}
+
+impl From<&Result<(), warp::reject::Rejection>> for Outcome {
+    fn from(result: &Result<(), warp::reject::Rejection>) -> Self {
+        let Err(rejection) = result else {
+            return Outcome::Delivered;
+        };
+
+        if rejection.is_not_found() {
+            Outcome::NotFound
+        } else if rejection.find::<CreateIOFail>().is_some() {
+            Outcome::CreateIoFail
+        } else if rejection.find::<OwnershipFail>().is_some() {
+            Outcome::OwnershipFail
+        } else if rejection.find::<SinkFail>().is_some() {
+            Outcome::SinkFail
+        } else if rejection.find::<InvalidPayload>().is_some() {
+            Outcome::InvalidPayload
+        } else if rejection.find::<EncryptionRequired>().is_some() {
+            Outcome::EncryptionRequired
+        } else if let Some(CommandFail { timeout, message }) = rejection.find::<CommandFail>() {
+            Outcome::CommandFail {
+                timeout: *timeout,
+                message: message.clone(),
+            }
+        } else {
+            Outcome::WriteIoFail
+        }
+    }
+}
+
+impl Outcome {
+    pub fn to_result(&self) -> Result<(), warp::reject::Rejection> {
+        let rejection = match self {
+            Outcome::Delivered => return Ok(()),
+            Outcome::NotFound => warp::reject::not_found(),
+            Outcome::CreateIoFail => warp::reject::custom(CreateIOFail),
+            Outcome::WriteIoFail => warp::reject::custom(WriteIOFail),
+            Outcome::OwnershipFail => warp::reject::custom(OwnershipFail),
+            Outcome::SinkFail => warp::reject::custom(SinkFail),
+            Outcome::InvalidPayload => warp::reject::custom(InvalidPayload),
+            Outcome::EncryptionRequired => warp::reject::custom(EncryptionRequired),
+            Outcome::CommandFail { timeout, message } => warp::reject::custom(CommandFail {
+                timeout: *timeout,
+                message: message.clone(),
+            }),
+        };
+        Err(rejection)
+    }
+}
+
+// Settings specific to the receive side.
+#[derive(Default)]
+struct ServerSettings {
+    // Pre-shared token the sender must present on every request.
+    token: Option<super::memory::SecretBuffer>,
+    // Requires requests signed with the token instead of presenting it.
+    hmac: bool,
+    // Wraps every connection in mutual TLS.
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    // Handling of syscalls outside the allowlist of the network worker.
+    seccomp: super::sandbox::Violation,
+    // Serving stops when the sender didn't complete the session before.
+    deadline: Option<std::time::Instant>,
+    // Serves the metrics, see [super::metrics::serve].
+    metrics: Option<std::net::TcpListener>,
+}
+
+// The unprivileged account of the network worker, see [super::privsep].
+const DEFAULT_WORKER_USER: &str = "nobody";
+
+// Outcome of authenticating a request.
+#[derive(Debug, Clone, Copy, Default)]
+struct Authorization {
+    // Signed digest of the body, the body must match before it's delivered.
+    content_sha256: Option<[u8; 32]>,
+}
+
+// How the sender announced the body of an upload.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+pub struct Body {
+    // The body is an age file.
+    pub encrypted: bool,
+    // The content length, sizes the buffers of sinks that need the entire content up front.
+    //
+    // NOTE; Only a hint, the body is read to its end either way
+    pub length: Option<u64>,
+}
+
+// Where the process that delivers reports to, unset in the network worker.
+#[derive(Default)]
+pub struct Reporting {
+    pub notifier: Option<super::systemd::Notifier>,
+    pub audit: Option<super::audit::AuditLog>,
+}
+
+// Mutable state shared between upload handlers.
+pub struct ReceiverState {
+    env_files: super::env_file::EnvFiles,
+    nonces: super::signature::NonceStore,
+    pub session: std::sync::Mutex<super::session::Session>,
+    // Stops the server after the session completed.
+    shutdown: tokio::sync::Notify,
+    // Decrypts uploads encrypted to the guest.
+    identities: Vec<age::x25519::Identity>,
+    // Delivers uploads with root privileges, this process delivers them when unset.
+    writer: Option<super::privsep::WriterClient>,
+    // Reports progress to the service manager, only set in the process that delivers.
+    notifier: Option<super::systemd::Notifier>,
+    // Records every upload, only set in the process that delivers.
+    audit: Option<super::audit::AuditLog>,
+}
+
+impl ReceiverState {
+    pub fn new(
+        manifest: &super::Manifest,
+        identities: Vec<age::x25519::Identity>,
+        writer: Option<super::privsep::WriterClient>,
+        reporting: Reporting,
+    ) -> Self {
+        let Reporting { notifier, audit } = reporting;
+        let session = super::session::Session::new(manifest);
+        if let Some(notifier) = &notifier {
+            notifier.ready(&session.status());
+        }
+
+        ReceiverState {
+            identities,
+            writer,
+            notifier,
+            audit,
+            env_files: Default::default(),
+            nonces: Default::default(),
+            session: std::sync::Mutex::new(session),
+            shutdown: tokio::sync::Notify::new(),
+        }
+    }
+
+    // Records the outcome of an upload delivered by this process.
+    pub fn record_delivery(&self, name: &str, path: &str, outcome: Result<(), String>) {
+        let status = {
+            let mut session = self.session.lock().expect("session lock poisoned");
+            session.record_delivery(name, path, outcome);
+            session.status()
+        };
+        if let Some(notifier) = &self.notifier {
+            notifier.progress(&status);
+        }
+    }
+
+    // Appends an upload delivered by this process to the audit log, if there is one.
+    pub fn audit(&self, transfer: super::audit::Transfer) {
+        if let Some(audit) = &self.audit {
+            audit.record(transfer);
+        }
+    }
+
+    // Completes the session of the uploads delivered by this process, see
+    // [super::session::complete].
+    pub async fn complete(
+        &self,
+        manifest: &super::Manifest,
+        completion: &super::session::Completion,
+    ) -> super::session::Report {
+        let report = super::session::complete(manifest, &self.session, completion).await;
+        if let Some(notifier) = &self.notifier {
+            notifier.completed(&self.session.lock().expect("session lock poisoned").status());
+        }
+
+        report
+    }
+
+    // The report of the session, kept by the privileged writer if there is one.
+    async fn report(&self) -> super::session::Report {
+        if let Some(writer) = &self.writer {
+            match writer.report().await {
+                Ok(report) => return report,
+                Err(e) => log::error!(
+                    error:% = e;
+                    "Failed retrieving the report of the privileged writer"
+                ),
+            }
+        }
+
+        self.session.lock().expect("session lock poisoned").report()
+    }
+}
+
 pub fn server_main(
     settings: super::GlobalSettings,
     mut parser: lexopt::Parser,
@@ -13,9 +245,55 @@ pub fn server_main(
     use lexopt::prelude::*;
 
     let mut manifest_path = None;
+    let mut identity = super::selector::Identity::detect_local();
+    let mut server_settings = ServerSettings::default();
+    let mut identities = Vec::new();
+    let mut worker_user = DEFAULT_WORKER_USER.to_string();
+    let mut audit_path = None;
+    let mut metrics_address: Option<std::net::SocketAddr> = None;
+    let mut dry_run = false;
 
     while let Some(arg) = parser.next()? {
         match arg {
+            Long("cid") => {
+                identity.cid = Some(parser.value()?.parse()?);
+            }
+            Long("hostname") => {
+                identity.hostname = Some(parser.value()?.string()?);
+            }
+            Long("role") => {
+                identity.roles.push(parser.value()?.string()?);
+            }
+            Long("token-file") => {
+                let path: std::path::PathBuf = parser.value()?.into();
+                server_settings.token = Some(super::token::read_token_file(&path)?);
+            }
+            Long("token-cmdline") => {
+                let key = parser.value()?.string()?;
+                server_settings.token = Some(super::token::read_token_cmdline(&key)?);
+            }
+            Long("hmac") => {
+                server_settings.hmac = true;
+            }
+            Long("age-identity") => {
+                let path: std::path::PathBuf = parser.value()?.into();
+                identities.extend(super::encryption::read_identities(&path)?);
+            }
+            Long("worker-user") => {
+                worker_user = parser.value()?.string()?;
+            }
+            Long("seccomp-log") => {
+                server_settings.seccomp = super::sandbox::Violation::Log;
+            }
+            Long("audit-log") => {
+                audit_path = Some(std::path::PathBuf::from(parser.value()?));
+            }
+            Long("dry-run") => {
+                dry_run = true;
+            }
+            Long("metrics-address") => {
+                metrics_address = Some(parser.value()?.parse()?);
+            }
             Value(value) if manifest_path.is_none() => {
                 manifest_path = Some(value.into());
             }
@@ -31,92 +309,1088 @@ pub fn server_main(
         }
     };
 
-    let manifest: &'static _ = super::read_and_deserialize_manifest(manifest_path);
+    if server_settings.hmac && server_settings.token.is_none() {
+        return Err("--hmac requires a pre-shared token".into());
+    }
+
+    let manifest: &'static _ = super::read_and_deserialize_manifest(manifest_path, &identity)?;
+
+    if dry_run {
+        return plan_deliveries(manifest, &identities);
+    }
+
+    // Fail early instead of refusing every encrypted upload
+    for secret in manifest.secrets.iter() {
+        let Some(recipient) = &secret.recipient else {
+            continue;
+        };
+        let decryptable = identities
+            .iter()
+            .any(|identity| identity.to_public().to_string() == *recipient);
+        if !decryptable {
+            return Err(format!(
+                "secret '{}' is encrypted to '{}', which matches none of the --age-identity keys",
+                secret.name, recipient
+            )
+            .into());
+        }
+    }
+
+    // Everything that requires privileges is read and bound before splitting off the worker
+    server_settings.tls = super::tls::acceptor(&settings.tls)?;
+    let activated = super::systemd::receive_socket(super::systemd::listen_fds()?)?;
+    let mut listener = match activated {
+        // The socket unit decides the address, the address options are ignored
+        Some(socket) => super::transport::Listener::adopt(socket.fd).map_err(|e| {
+            format!(
+                "failed to adopt socket '{}' of the service manager: {}",
+                socket.name, e
+            )
+        })?,
+        None => {
+            let address = super::transport::Address::from_settings(&settings)?;
+            address
+                .bind()
+                .map_err(|e| format!("failed to listen on {}: {}", address, e))?
+        }
+    };
+
+    if let Some(address) = metrics_address {
+        server_settings.metrics = Some(
+            std::net::TcpListener::bind(address)
+                .map_err(|e| format!("failed to serve metrics on {}: {}", address, e))?,
+        );
+    }
+
+    // NOTE; The deadline starts before splitting off the worker, which stops serving at it
+    let deadline =
+        std::time::Instant::now() + std::time::Duration::from_secs(settings.timeout_seconds.into());
+    server_settings.deadline = Some(deadline);
+
+    // NOTE; Opened before confining writes, which don't cover the directory of the log
+    let audit = audit_path
+        .map(|path| super::audit::AuditLog::open(&path, super::audit::Side::Receive))
+        .transpose()?;
+
+    // Missing destinations are refused before splitting off the worker
+    let write_rules = write_rules(manifest, &listener)?;
+
+    if unsafe { libc::geteuid() } != 0 {
+        log::warn!("Not running as root, privilege separation is disabled");
+        // NOTE; No syscall filter, this process also starts the commands and hooks of sinks
+        super::sandbox::restrict_capabilities(&super::sandbox::delivery_capabilities(manifest))?;
+        super::sandbox::set_no_new_privs()?;
+        super::sandbox::confine_writes(write_rules)?;
+        let reporting = Reporting {
+            notifier: super::systemd::Notifier::from_environment(deadline),
+            audit,
+        };
+        return run_server(
+            &settings,
+            server_settings,
+            manifest,
+            identities,
+            listener,
+            None,
+            reporting,
+        );
+    }
+
+    let (uid, gid) = super::ownership::lookup_account(&worker_user)?;
+    if uid == 0 {
+        return Err(format!(
+            "refusing to run the network worker as root user '{}'",
+            worker_user
+        )
+        .into());
+    }
+    match super::privsep::split(uid, gid)
+        .map_err(|e| format!("failed to start the network worker: {}", e))?
+    {
+        super::privsep::Role::Worker(socket) => {
+            // NOTE; The writer unlinks the socket path, the worker lacks the permission
+            listener.disarm();
+            // Only the writer appends to the audit log and decrypts
+            drop(audit);
+            drop(identities);
+            run_server(
+                &settings,
+                server_settings,
+                manifest,
+                Vec::new(),
+                listener,
+                Some(socket),
+                Reporting::default(),
+            )
+        }
+        super::privsep::Role::Writer(socket, worker) => {
+            // The worker serves the peer and the metrics
+            drop(server_settings);
+            super::sandbox::restrict_capabilities(&super::sandbox::delivery_capabilities(
+                manifest,
+            ))?;
+            super::sandbox::set_no_new_privs()?;
+            super::sandbox::confine_writes(write_rules)?;
+            let reporting = Reporting {
+                notifier: super::systemd::Notifier::from_environment(deadline),
+                audit,
+            };
+            let result = super::privsep::run_writer(manifest, socket, identities, reporting);
+            let status = super::privsep::wait(worker)?;
+            drop(listener);
+            result?;
+            if status != 0 {
+                // The worker reported the cause
+                std::process::exit(status);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Checks every secret could be delivered, without listening. Writability isn't checked, it
+// depends on the sandbox and on filesystems mounted at delivery.
+fn plan_deliveries(
+    manifest: &super::Manifest,
+    identities: &[age::x25519::Identity],
+) -> Result<(), Box<dyn std::error::Error>> {
+    use super::ownership;
+
+    let mut entries = Vec::new();
+    for secret in manifest.secrets.iter() {
+        let mut problems = Vec::new();
+        let uid = ownership::lookup_user(&secret.owner)
+            .map_err(|e| problems.push(e))
+            .ok();
+        let gid = ownership::lookup_group(&secret.group)
+            .map_err(|e| problems.push(e))
+            .ok();
+        if let Err(e) = ownership::parse_mode(&secret.mode) {
+            problems.push(e);
+        }
+        if let Some(recipient) = &secret.recipient {
+            let decryptable = identities
+                .iter()
+                .any(|identity| identity.to_public().to_string() == *recipient);
+            if !decryptable {
+                problems.push(format!(
+                    "encrypted to '{}', which matches none of the --age-identity keys",
+                    recipient
+                ));
+            }
+        }
+        problems.extend(check_sink(secret));
+
+        entries.push(super::plan::Entry {
+            detail: super::plan::Delivery {
+                secret: secret.name.clone(),
+                destination: secret
+                    .sink
+                    .describe_destination(&secret.name, secret.destination_path.as_deref()),
+                owner: secret.owner.clone(),
+                group: secret.group.clone(),
+                mode: secret.mode.clone(),
+                uid,
+                gid,
+                encrypted: secret.recipient.is_some(),
+            },
+            problems,
+        });
+    }
+
+    super::plan::print(super::audit::Side::Receive, &entries)
+}
+
+// Problems of the destination of the sink that would fail the delivery.
+fn check_sink(secret: &super::Secret) -> Vec<String> {
+    use super::sink::Sink;
+    use std::path::Path;
+
+    let mut problems = Vec::new();
+    // Directories that are created on delivery need an existing directory as nearest ancestor
+    let check_creatable = |directory: &Path, problems: &mut Vec<String>| match directory
+        .ancestors()
+        .find(|path| path.exists())
+    {
+        Some(existing) if !existing.is_dir() => {
+            problems.push(format!("'{}' is not a directory", existing.display()))
+        }
+        _ => {}
+    };
+    let check_directory = |directory: Option<&Path>, problems: &mut Vec<String>| {
+        let directory = directory.unwrap_or(Path::new("/"));
+        if !directory.is_dir() {
+            problems.push(format!(
+                "directory '{}' does not exist",
+                directory.display()
+            ));
+        }
+    };
+
+    match (&secret.sink, &secret.destination_path) {
+        (Sink::File, Some(destination)) if secret.is_tree() => {
+            check_creatable(destination, &mut problems);
+        }
+        (Sink::File, Some(destination)) => {
+            check_directory(destination.parent(), &mut problems);
+            if destination.is_dir() {
+                problems.push(format!("'{}' is a directory", destination.display()));
+            }
+        }
+        (Sink::Credential(sink), _) => {
+            check_creatable(&super::credential::directory(sink), &mut problems);
+            if sink.encrypted && find_program("systemd-creds").is_none() {
+                problems.push("encrypted credentials require systemd-creds".to_string());
+            }
+        }
+        (Sink::EnvFile(sink), _) => {
+            check_directory(sink.path.parent(), &mut problems);
+        }
+        (Sink::Command(command), _) => match command.argv.first() {
+            Some(program) if find_program(program).is_none() => {
+                problems.push(format!("command '{}' not found", program));
+            }
+            Some(_) => {}
+            None => problems.push("command has no arguments".to_string()),
+        },
+        _ => {}
+    }
 
-    // TODO Create socket listener based on inputs
-    run_server(&settings, manifest)
+    problems
+}
+
+// Resolves a program like the commands of sinks do, either a path or a name searched in PATH.
+fn find_program(program: &str) -> Option<std::path::PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let is_executable = |path: &std::path::Path| {
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        let path = std::path::PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+
+    let search_path = std::env::var_os("PATH")?;
+    std::env::split_paths(&search_path)
+        .map(|directory| directory.join(program))
+        .find(|path| is_executable(path))
+}
+
+// Rules restricting writes to the destinations of the manifest, and to the socket path for
+// unlinking it.
+fn write_rules(
+    manifest: &super::Manifest,
+    listener: &super::transport::Listener,
+) -> std::io::Result<super::sandbox::WriteRules> {
+    let socket_directory = listener.unix_path().and_then(|path| path.parent());
+    super::sandbox::write_rules(&super::sandbox::writable_paths(manifest), socket_directory)
 }
 
 #[tokio::main]
 async fn run_server(
     settings: &super::GlobalSettings,
+    mut server_settings: ServerSettings,
     manifest: &'static super::Manifest,
+    identities: Vec<age::x25519::Identity>,
+    listener: super::transport::Listener,
+    writer: Option<std::os::unix::net::UnixStream>,
+    reporting: Reporting,
 ) -> Result<(), Box<dyn std::error::Error>> {
     use warp::Filter;
-    let manifest_tracker = std::sync::Arc::new(());
+    let writer = writer.map(super::privsep::WriterClient::new).transpose()?;
+    let metrics: &'static _ = Box::leak(Box::new(super::metrics::Metrics::new(manifest)));
+    if let Some(metrics_listener) = server_settings.metrics.take() {
+        super::metrics::serve(metrics_listener, metrics)?;
+    }
+    let mut incoming = listener.incoming()?;
+    if let Some(acceptor) = server_settings.tls.clone() {
+        incoming = super::tls::accept(incoming, acceptor, metrics);
+    }
+    // The worker only talks to the peer and the writer from here on
+    if writer.is_some() {
+        super::sandbox::apply_worker_filter(server_settings.seccomp)?;
+    }
+
+    let manifest_tracker = std::sync::Arc::new(super::StateType::new(
+        manifest, identities, writer, reporting,
+    ));
+    let shutdown_tracker = manifest_tracker.clone();
+    // Serving stops at the deadline when the sender didn't complete the session before
+    let deadline = server_settings.deadline;
 
     // Wrap data for injecting into route handlers
     let state = warp::any().map(move || (manifest, manifest_tracker.clone()));
 
+    // Authorization is verified before resolving secrets, so rejections don't reveal which
+    // secrets exist
+    let server_settings = std::sync::Arc::new(server_settings);
+    let authorized = warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<super::transport::Peer>())
+        .and(warp::ext::get::<super::transport::ConnectionId>())
+        .and(state.clone())
+        .and_then(
+            move |method, path: warp::path::FullPath, headers, peer, connection, state| {
+                let server_settings = server_settings.clone();
+                async move {
+                    let result =
+                        authorize(&server_settings, method, &path, headers, connection, state);
+                    if result.is_err() {
+                        metrics.record_auth_failure();
+                        log::warn!(
+                            peer:% = describe_peer(&peer), path = path.as_str();
+                            "Refusing unauthorized request"
+                        );
+                    }
+                    result
+                }
+            },
+        );
+
     // POST /secrets/:name  <binary data>
+    // POST /secrets/:name/:relative_path..  <binary data>
     let upload_route = warp::post()
         .and(warp::path("secrets"))
-        .and(warp::path::param())
+        .and(authorized.clone())
+        .and(warp::path::tail())
+        .and(
+            warp::header::optional::<String>(super::encryption::ENCRYPTION_HEADER)
+                .and(warp::header::optional::<u64>("content-length"))
+                .map(|encryption, length| (encryption, length)),
+        )
         .and(warp::body::content_length_limit(
             settings.max_transmission_bytes.into(),
         ))
         .and(warp::body::stream())
-        .and(state)
+        .and(warp::ext::optional::<super::transport::Peer>())
+        .and(warp::any().map(move || metrics))
+        .and(state.clone())
         .and_then(handle_upload);
 
-    let router = upload_route.recover(handle_rejection);
+    // POST /session/complete
+    let complete_route = warp::post()
+        .and(warp::path!("session" / "complete"))
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(MAX_COMPLETION_LENGTH))
+        .and(warp::body::bytes())
+        .and(state.clone())
+        .then(handle_complete);
+
+    // GET /status
+    let status_route = warp::get()
+        .and(warp::path!("status"))
+        .and(authorized)
+        .and(state.clone())
+        .then(handle_status);
+
+    // GET /session/nonce
+    let nonce_route = warp::get()
+        .and(warp::path!("session" / "nonce"))
+        .and(warp::ext::get::<super::transport::ConnectionId>())
+        .and(state.clone())
+        .and_then(handle_nonce);
+
+    let router = nonce_route
+        .or(upload_route)
+        .or(complete_route)
+        .or(status_route)
+        .recover(handle_rejection);
+
+    let shutdown_signal = {
+        let tracker = shutdown_tracker.clone();
+        async move {
+            let expired = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = tracker.shutdown.notified() => {}
+                _ = expired => {}
+            }
+        }
+    };
+    // Every request carries the peer and the identifier of its connection, see
+    // [super::transport::Peer]
+    let service = warp::service(router);
+    let mut next_connection = 0;
+    let make_service =
+        hyper_0_14::service::make_service_fn(move |accepted: &super::transport::Accepted| {
+            let peer = accepted.peer.clone();
+            let connection = super::transport::ConnectionId(next_connection);
+            next_connection += 1;
+            let service = service.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper_0_14::service::service_fn(
+                    move |mut request| {
+                        use hyper_0_14::service::Service;
 
-    if cfg!(not(unix)) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Must run under Unix-like platform!",
-        ))?;
+                        request.extensions_mut().insert(peer.clone());
+                        request.extensions_mut().insert(connection);
+                        service.clone().call(request)
+                    },
+                ))
+            }
+        });
+    let server = hyper_0_14::Server::builder(hyper_0_14::server::accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown_signal);
+    if let Err(e) = server.await {
+        log::error!(error:% = e; "HTTP server failed");
+    }
+
+    let report = shutdown_tracker.report().await;
+    if !report.completed {
+        return Err(format!(
+            "session not completed within {} seconds: {}",
+            settings.timeout_seconds,
+            serde_json::to_string(&report)?
+        )
+        .into());
+    }
+    if !report.success {
+        return Err(format!(
+            "session completed with failures: {}",
+            serde_json::to_string(&report)?
+        )
+        .into());
     }
 
-    let mut listener = super::unix_socket::DeleteOnDrop::bind("/tmp/warp.sock")?;
-    Ok(warp::serve(router).run_incoming(&mut *listener).await)
+    Ok(())
+}
+
+// Splits the request path into the addressed secret and its destination path, if any. Directory
+// entries expect the path relative to their destination directory after the secret name.
+pub fn resolve_destination(
+    manifest: &'static super::Manifest,
+    tail: &str,
+) -> Option<(&'static super::Secret, Option<std::path::PathBuf>)> {
+    let mut segments = tail.split('/').map(|segment| {
+        percent_encoding::percent_decode_str(segment)
+            .decode_utf8()
+            .ok()
+    });
+    let name = segments.next()??;
+    let secret = manifest.secrets.iter().find(|&item| item.name == name)?;
+    let relative = segments.collect::<Option<Vec<_>>>()?;
+
+    match (secret.is_tree(), relative.is_empty()) {
+        (false, true) => Some((secret, secret.destination_path.clone())),
+        (true, false) => {
+            // WARN; Segments are peer controlled, never allow escaping the destination directory!
+            let unsafe_segment = relative.iter().any(|segment| {
+                segment.is_empty()
+                    || segment == "."
+                    || segment == ".."
+                    || segment.contains(['/', '\0'])
+            });
+            if unsafe_segment {
+                return None;
+            }
+
+            let destination = relative
+                .iter()
+                .fold(secret.destination_path.clone()?, |path, segment| {
+                    path.join(segment.as_ref())
+                });
+            Some((secret, Some(destination)))
+        }
+        _ => None,
+    }
+}
+
+// Verifies the bearer token or the request signature, depending on the settings.
+fn authorize(
+    server_settings: &ServerSettings,
+    method: warp::http::Method,
+    path: &warp::path::FullPath,
+    headers: warp::http::HeaderMap,
+    connection: super::transport::ConnectionId,
+    (_manifest, tracker): (&'static super::Manifest, std::sync::Arc<super::StateType>),
+) -> Result<Authorization, warp::reject::Rejection> {
+    use super::signature;
+
+    let token = match &server_settings.token {
+        Some(token) => token,
+        None => return Ok(Authorization::default()),
+    };
+
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    if !server_settings.hmac {
+        return match super::token::verify(token, header("authorization")) {
+            true => Ok(Authorization::default()),
+            false => Err(warp::reject::custom(Unauthorized)),
+        };
+    }
+
+    let (Some(timestamp), Some(nonce), Some(content_sha256), Some(request_signature)) = (
+        header(signature::TIMESTAMP_HEADER),
+        header(signature::NONCE_HEADER),
+        header(signature::CONTENT_SHA256_HEADER),
+        header(signature::SIGNATURE_HEADER),
+    ) else {
+        return Err(warp::reject::custom(Unauthorized));
+    };
+
+    let signature_valid = signature::verify(
+        token,
+        method.as_str(),
+        path.as_str(),
+        content_sha256,
+        timestamp,
+        nonce,
+        request_signature,
+    );
+    // NOTE; The nonce is only consumed by correctly signed requests
+    if !signature_valid
+        || !signature::is_fresh(timestamp)
+        || !tracker.nonces.consume(connection, nonce)
+    {
+        return Err(warp::reject::custom(Unauthorized));
+    }
+
+    let mut digest = [0u8; 32];
+    hex::decode_to_slice(content_sha256, &mut digest)
+        .map_err(|_| warp::reject::custom(Unauthorized))?;
+    Ok(Authorization {
+        content_sha256: Some(digest),
+    })
+}
+
+async fn handle_nonce(
+    connection: super::transport::ConnectionId,
+    (_manifest, tracker): (&'static super::Manifest, std::sync::Arc<super::StateType>),
+) -> Result<impl warp::reply::Reply, warp::reject::Rejection> {
+    match tracker.nonces.issue(connection) {
+        Ok(nonce) => Ok(nonce),
+        Err(e) => {
+            log::error!(error:% = e; "Failed to generate nonce");
+            Err(warp::reject::custom(NonceUnavailable))
+        }
+    }
 }
 
 async fn handle_upload(
-    tag: String,
+    authorization: Authorization,
+    tail: warp::path::Tail,
+    (encryption, length): (Option<String>, Option<u64>),
     file_body: impl futures::Stream<Item = Result<impl warp::Buf, warp::Error>> + Unpin,
-    (manifest, _tracker): (&'static super::Manifest, std::sync::Arc<super::StateType>),
+    peer: Option<super::transport::Peer>,
+    metrics: &'static super::metrics::Metrics,
+    (manifest, tracker): (&'static super::Manifest, std::sync::Arc<super::StateType>),
 ) -> Result<impl warp::reply::Reply, warp::reject::Rejection> {
-    // TODO Verify with state
-    let secret = match manifest.secrets.iter().find(|&item| item.name == tag) {
-        Some(secret) => secret,
-        None => return Err(warp::reject::not_found()),
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio_stream::StreamExt;
+
+    let started = std::time::Instant::now();
+    let received = AtomicU64::new(0);
+    let file_body = file_body.map(|result| {
+        result.inspect(|chunk| {
+            received.fetch_add(chunk.remaining() as u64, Ordering::Relaxed);
+        })
+    });
+    let peer = describe_peer(&peer);
+    let result = upload(
+        authorization,
+        tail.as_str(),
+        encryption,
+        length,
+        file_body,
+        &peer,
+        (manifest, &tracker),
+    )
+    .await;
+
+    let secret = resolve_destination(manifest, tail.as_str())
+        .map_or(tail.as_str(), |(secret, _)| secret.name.as_str());
+    let bytes = received.load(Ordering::Relaxed);
+    let outcome = match &result {
+        Ok(()) => "delivered",
+        Err(rejection) => describe_outcome(rejection),
     };
+    metrics.record_upload(secret, outcome, bytes, started.elapsed());
+    match &result {
+        Ok(()) => log::info!(secret, peer:%, bytes, outcome; "Upload delivered"),
+        Err(_) => log::warn!(secret, peer:%, bytes, outcome; "Upload failed"),
+    }
 
-    // TODO Resolve physical destination node
-    let target_file_path = &secret.destination_path;
+    result.map(|_| warp::http::StatusCode::CREATED)
+}
 
-    // Open the file in write mode
-    let mut target_file_handle = match tokio::fs::File::create(target_file_path).await {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("Failed to create file: {}", e);
-            return Err(warp::reject::custom(CreateIOFail));
-        }
+// Resolves the upload to its secret and delivers the body.
+async fn upload(
+    authorization: Authorization,
+    tail: &str,
+    encryption: Option<String>,
+    length: Option<u64>,
+    file_body: impl futures::Stream<Item = Result<impl warp::Buf, warp::Error>> + Unpin,
+    peer: &super::transport::Peer,
+    (manifest, tracker): (&'static super::Manifest, &super::StateType),
+) -> Result<(), warp::reject::Rejection> {
+    let (secret, target_file_path) = match resolve_destination(manifest, tail) {
+        Some(found) => found,
+        None => return Err(warp::reject::not_found()),
     };
 
+    let encrypted = match encryption.as_deref() {
+        None => false,
+        Some(super::encryption::AGE) => true,
+        Some(_) => return Err(warp::reject::custom(InvalidPayload)),
+    };
+    if secret.recipient.is_some() && !encrypted {
+        return Err(warp::reject::custom(EncryptionRequired));
+    }
+    let body = Body { encrypted, length };
+
     // Use StreamExt to map the stream and error to a std::io::Error, tokio::io::copy* methods
     // require the stream elements to error with std::io::Error type.
+    //
+    // NOTE; The chunks of the body are owned by the HTTP server and not wiped, buffers from
+    // here on are.
     use tokio_stream::StreamExt;
-    let file_body = file_body
-        .map(|result| result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)));
+    let file_body = file_body.map(|result| result.map_err(std::io::Error::other));
     let mut file_body = tokio_util::io::StreamReader::new(file_body);
 
+    match authorization.content_sha256 {
+        None => {
+            receive(
+                secret,
+                target_file_path,
+                tail,
+                body,
+                &mut file_body,
+                peer,
+                tracker,
+            )
+            .await
+        }
+        Some(expected) => {
+            // The body must match the signed digest before anything is delivered
+            let payload = read_payload(&mut file_body, length).await?;
+            if super::signature::content_sha256(&payload) != expected {
+                return Err(warp::reject::custom(InvalidPayload));
+            }
+            let mut payload = payload.as_slice();
+            receive(
+                secret,
+                target_file_path,
+                tail,
+                body,
+                &mut payload,
+                peer,
+                tracker,
+            )
+            .await
+        }
+    }
+}
+
+// Decrypts the body, if the sender encrypted it, on its way to the sink. The outcome is recorded
+// by whoever delivers the body.
+async fn receive(
+    secret: &'static super::Secret,
+    target_file_path: Option<std::path::PathBuf>,
+    tail: &str,
+    body: Body,
+    file_body: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    peer: &super::transport::Peer,
+    tracker: &super::StateType,
+) -> Result<(), warp::reject::Rejection> {
+    let mut upload = match &tracker.writer {
+        None => None,
+        Some(writer) => match writer.create(tail, &peer.to_string(), body).await {
+            Ok(upload) => Some(upload),
+            Err(e) => {
+                log::error!(
+                    secret = secret.name.as_str(), error:% = e;
+                    "Failed handing the upload to the privileged writer"
+                );
+                return Err(warp::reject::custom(WriteIOFail));
+            }
+        },
+    };
+
+    let destination = secret
+        .sink
+        .describe_destination(&secret.name, target_file_path.as_deref());
+    let mut content = super::audit::Content::default();
+    let result = match upload.as_mut() {
+        // NOTE; The writer decrypts, the worker holds no identities
+        Some(upload) => upload.write_from(file_body).await.map_err(|e| {
+            log::error!(
+                secret = secret.name.as_str(), error:% = e;
+                "Failed streaming the upload to the privileged writer"
+            );
+            read_failure(e)
+        }),
+        None => {
+            decrypt_and_deliver(
+                secret,
+                target_file_path,
+                body,
+                file_body,
+                &mut content,
+                tracker,
+            )
+            .await
+        }
+    };
+
+    let Some(upload) = upload else {
+        tracker.audit(super::audit::Transfer {
+            peer: peer.to_string(),
+            secret: secret.name.clone(),
+            digest: result.is_ok().then(|| content.digest()),
+            size: content.size(),
+            destination,
+            outcome: match &result {
+                Ok(()) => "delivered",
+                Err(rejection) => describe_outcome(rejection),
+            }
+            .to_string(),
+        });
+        tracker.record_delivery(
+            &secret.name,
+            tail,
+            result.as_ref().map(|_| ()).map_err(|e| format!("{:?}", e)),
+        );
+        return result;
+    };
+
+    match result {
+        Ok(()) => match upload.commit().await {
+            Ok(outcome) => outcome.to_result(),
+            Err(e) => {
+                log::error!(
+                    secret = secret.name.as_str(), error:% = e;
+                    "Failed committing the upload to the privileged writer"
+                );
+                Err(warp::reject::custom(WriteIOFail))
+            }
+        },
+        Err(rejection) => {
+            upload.abort(&format!("{:?}", rejection)).await;
+            Err(rejection)
+        }
+    }
+}
+
+// Decrypts the body, if the sender encrypted it, and delivers the plaintext in this process,
+// adding it to the content. Only the identity of the recipient of the secret decrypts it.
+pub async fn decrypt_and_deliver(
+    secret: &'static super::Secret,
+    target_file_path: Option<std::path::PathBuf>,
+    body: Body,
+    file_body: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    content: &mut super::audit::Content,
+    tracker: &super::StateType,
+) -> Result<(), warp::reject::Rejection> {
+    if !body.encrypted {
+        if secret.recipient.is_some() {
+            return Err(warp::reject::custom(EncryptionRequired));
+        }
+        let mut file_body = super::audit::Digesting::new(file_body, content);
+        return deliver(
+            secret,
+            target_file_path,
+            &mut file_body,
+            body.length,
+            tracker,
+        )
+        .await;
+    }
+
+    let identities = tracker.identities.iter().filter(|identity| {
+        secret
+            .recipient
+            .as_ref()
+            .is_none_or(|recipient| identity.to_public().to_string() == *recipient)
+    });
+    match super::encryption::decrypt(identities, file_body).await {
+        Ok(mut plaintext) => {
+            // NOTE; The length of the ciphertext only bounds the plaintext, it's no hint
+            let mut file_body = super::audit::Digesting::new(&mut plaintext, content);
+            deliver(secret, target_file_path, &mut file_body, None, tracker).await
+        }
+        Err(e) => {
+            log::warn!(secret = secret.name.as_str(), error:% = e; "Failed decrypting upload");
+            Err(warp::reject::custom(InvalidPayload))
+        }
+    }
+}
+
+// Hands the body to the sink of the secret.
+pub async fn deliver(
+    secret: &'static super::Secret,
+    target_file_path: Option<std::path::PathBuf>,
+    file_body: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    length: Option<u64>,
+    tracker: &super::StateType,
+) -> Result<(), warp::reject::Rejection> {
+    let ownership = match super::ownership::Ownership::resolve(
+        &secret.owner,
+        &secret.group,
+        &secret.mode,
+    ) {
+        Ok(o) => o,
+        Err(e) => {
+            log::error!(secret = secret.name.as_str(), error:% = e; "Failed to resolve ownership");
+            return Err(warp::reject::custom(OwnershipFail));
+        }
+    };
+
+    match (&secret.sink, target_file_path) {
+        (super::sink::Sink::File, Some(target_file_path)) => {
+            deliver_file(secret, &target_file_path, &ownership, file_body).await?;
+        }
+        (super::sink::Sink::Keyring(keyring), _) => {
+            let payload = read_payload(file_body, length).await?;
+            let description = keyring
+                .description
+                .clone()
+                .unwrap_or_else(|| secret.name.clone());
+            let result = tokio::task::spawn_blocking(move || {
+                super::keyring::store(keyring, &description, &payload, &ownership)
+            })
+            .await;
+            if let Err(e) = result.map_err(std::io::Error::other).and_then(|r| r) {
+                log::error!(secret = secret.name.as_str(), error:% = e; "Failed storing into keyring");
+                return Err(warp::reject::custom(SinkFail));
+            }
+        }
+        (super::sink::Sink::Credential(credential), _) => {
+            let payload = read_payload(file_body, length).await?;
+            let name = credential.name.as_deref().unwrap_or(&secret.name);
+            let target_file_path = match super::credential::prepare_destination(credential, name)
+                .await
+            {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!(secret = secret.name.as_str(), error:% = e; "Failed to prepare credential store");
+                    return Err(warp::reject::custom(CreateIOFail));
+                }
+            };
+            let content = match credential.encrypted {
+                false => payload,
+                true => match super::credential::encrypt(credential, name, &payload)
+                    .await
+                    .and_then(super::memory::SecretBuffer::try_from)
+                {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log::error!(secret = secret.name.as_str(), error:% = e; "Failed encrypting credential");
+                        return Err(warp::reject::custom(SinkFail));
+                    }
+                },
+            };
+            deliver_file(
+                secret,
+                &target_file_path,
+                &super::credential::CREDENTIAL_OWNERSHIP,
+                &mut content.as_slice(),
+            )
+            .await?;
+        }
+        (super::sink::Sink::EnvFile(env_file), _) => {
+            let payload = read_payload(file_body, length).await?;
+            let value = match super::env_file::parse_value(&payload) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!(secret = secret.name.as_str(), error:% = e; "Refusing value for environment file");
+                    return Err(warp::reject::custom(InvalidPayload));
+                }
+            };
+            let key = env_file.key(&secret.name);
+            if let Err(e) = tracker
+                .env_files
+                .update(&env_file.path, key, value, &ownership)
+                .await
+            {
+                log::error!(secret = secret.name.as_str(), error:% = e; "Failed writing environment file");
+                return Err(warp::reject::custom(WriteIOFail));
+            }
+        }
+        (super::sink::Sink::Command(command), _) => {
+            if let Err(e) = super::command::run(command, &secret.name, &ownership, file_body).await
+            {
+                log::error!(secret = secret.name.as_str(), error:% = e; "Failed delivering to command");
+                return Err(warp::reject::custom(CommandFail {
+                    timeout: matches!(e, super::command::CommandError::Timeout),
+                    message: e.to_string(),
+                }));
+            }
+        }
+        (super::sink::Sink::File, None) => {
+            // NOTE; Manifest validation guarantees a destination for file sinks
+            return Err(warp::reject::custom(SinkFail));
+        }
+    }
+
+    Ok(())
+}
+
+// The completion lists directory entries by secret, it stays far below this.
+const MAX_COMPLETION_LENGTH: u64 = 64 * 1024;
+
+// Runs the post-delivery hooks and stops the server after the response is sent. Senders without a
+// completion body, eg; older ones, don't plan any directory entries.
+async fn handle_complete(
+    authorization: Authorization,
+    body: bytes::Bytes,
+    (manifest, tracker): (&'static super::Manifest, std::sync::Arc<super::StateType>),
+) -> warp::reply::Response {
+    use warp::reply::Reply;
+
+    if let Some(expected) = authorization.content_sha256 {
+        if super::signature::content_sha256(&body) != expected {
+            return warp::http::StatusCode::BAD_REQUEST.into_response();
+        }
+    }
+    let completion = match body.is_empty() {
+        true => super::session::Completion::default(),
+        false => match serde_json::from_slice(&body) {
+            Ok(completion) => completion,
+            Err(e) => {
+                log::warn!(error:% = e; "Received a malformed completion");
+                return warp::http::StatusCode::BAD_REQUEST.into_response();
+            }
+        },
+    };
+
+    let report = match &tracker.writer {
+        None => tracker.complete(manifest, &completion).await,
+        Some(writer) => match writer.complete(&completion).await {
+            Ok(report) => report,
+            Err(e) => {
+                log::error!(
+                    error:% = e;
+                    "Failed completing the session of the privileged writer"
+                );
+                tracker.report().await
+            }
+        },
+    };
+    tracker.shutdown.notify_one();
+
+    let code = match report.success {
+        true => warp::http::StatusCode::OK,
+        false => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warp::reply::with_status(warp::reply::json(&report), code).into_response()
+}
+
+async fn handle_status(
+    _authorization: Authorization,
+    (_manifest, tracker): (&'static super::Manifest, std::sync::Arc<super::StateType>),
+) -> impl warp::reply::Reply {
+    let report = tracker.report().await;
+    warp::reply::json(&report)
+}
+
+// Writes the body into a staged file and atomically moves it to the destination.
+async fn deliver_file(
+    secret: &super::Secret,
+    target_file_path: &std::path::Path,
+    ownership: &super::ownership::Ownership,
+    file_body: &mut (impl tokio::io::AsyncBufRead + Unpin),
+) -> Result<(), warp::reject::Rejection> {
+    if secret.is_tree() {
+        // Recreate the relative layout of the sender
+        if let Some(parent) = target_file_path.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                log::error!(secret = secret.name.as_str(), error:% = e; "Failed to create directory");
+                return Err(warp::reject::custom(CreateIOFail));
+            }
+        }
+    }
+
+    // Stage the content next to the destination, it's moved in place after a complete write
+    let mut staged_file = match super::atomic_file::StagedFile::create(target_file_path).await {
+        Ok(f) => f,
+        Err(e) => {
+            log::error!(secret = secret.name.as_str(), error:% = e; "Failed to create file");
+            return Err(warp::reject::custom(CreateIOFail));
+        }
+    };
+
     // TODO Shutdown stream if receiving data takes too long
-    let _bytes_written = match tokio::io::copy_buf(&mut file_body, &mut target_file_handle).await {
+    let _bytes_written = match tokio::io::copy_buf(file_body, &mut staged_file.file).await {
         Ok(b) => b,
         Err(e) => {
-            eprintln!("Failed writing to file: {}", e);
-            return Err(warp::reject::custom(WriteIOFail));
+            log::error!(secret = secret.name.as_str(), error:% = e; "Failed writing to file");
+            return Err(read_failure(e));
         }
     };
 
-    // TODO Update state
+    if let Err(e) = staged_file.commit(ownership).await {
+        log::error!(secret = secret.name.as_str(), error:% = e; "Failed committing file");
+        return Err(warp::reject::custom(WriteIOFail));
+    }
+
+    Ok(())
+}
+
+// Collects the body into memory for sinks that need the entire content at once. A known length
+// sizes the buffer up front, instead of growing it while reading.
+//
+// NOTE; The size of the body is bounded by the content length limit of the route.
+async fn read_payload(
+    file_body: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    length: Option<u64>,
+) -> Result<super::memory::SecretBuffer, warp::reject::Rejection> {
+    let capacity = length.and_then(|length| usize::try_from(length).ok());
+    let read = async {
+        let mut payload = super::memory::SecretBuffer::with_capacity(capacity.unwrap_or_default())?;
+        payload.read_from(file_body).await?;
+        Ok::<_, std::io::Error>(payload)
+    };
+    match read.await {
+        Ok(payload) => Ok(payload),
+        Err(e) => {
+            log::warn!(error:% = e; "Failed reading upload");
+            Err(read_failure(e))
+        }
+    }
+}
+
+// Body errors of kind InvalidData are raised by decryption, the peer sent a bad payload.
+fn read_failure(e: std::io::Error) -> warp::reject::Rejection {
+    match e.kind() {
+        std::io::ErrorKind::InvalidData => warp::reject::custom(InvalidPayload),
+        _ => warp::reject::custom(WriteIOFail),
+    }
+}
 
-    // TODO Signal shutdown
-    // shutdown_signal.send(()).await?;
+// Short name of the reason an upload failed, for logging and the audit log.
+pub fn describe_outcome(rejection: &warp::reject::Rejection) -> &'static str {
+    if rejection.is_not_found() {
+        "not_found"
+    } else if rejection.find::<EncryptionRequired>().is_some() {
+        "encryption_required"
+    } else if rejection.find::<InvalidPayload>().is_some() {
+        "invalid_payload"
+    } else if rejection.find::<CreateIOFail>().is_some() {
+        "create_io_fail"
+    } else if rejection.find::<OwnershipFail>().is_some() {
+        "ownership_fail"
+    } else if rejection.find::<SinkFail>().is_some() {
+        "sink_fail"
+    } else if let Some(CommandFail { timeout, .. }) = rejection.find::<CommandFail>() {
+        match timeout {
+            true => "command_timeout",
+            false => "command_fail",
+        }
+    } else {
+        "write_io_fail"
+    }
+}
 
-    Ok(warp::http::StatusCode::CREATED)
+fn describe_peer(peer: &Option<super::transport::Peer>) -> super::transport::Peer {
+    peer.clone().unwrap_or(super::transport::Peer::Unknown)
 }
 
 async fn handle_rejection(
@@ -124,12 +1398,30 @@ async fn handle_rejection(
 ) -> std::result::Result<impl warp::reply::Reply, std::convert::Infallible> {
     use warp::http::StatusCode;
 
-    let (code, message) = if err.is_not_found() {
+    let (code, message) = if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "Unauthorized".to_string())
+    } else if err.find::<NonceUnavailable>().is_some() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Service Unavailable".to_string(),
+        )
+    } else if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not Found".to_string())
     } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
         (StatusCode::BAD_REQUEST, "Payload too large".to_string())
+    } else if err.find::<InvalidPayload>().is_some() {
+        (StatusCode::BAD_REQUEST, "Invalid payload".to_string())
+    } else if err.find::<EncryptionRequired>().is_some() {
+        (StatusCode::BAD_REQUEST, "Encryption required".to_string())
+    } else if let Some(CommandFail { timeout, message }) = err.find::<CommandFail>() {
+        // The outcome of the command is the outcome of the upload
+        let code = match timeout {
+            true => StatusCode::GATEWAY_TIMEOUT,
+            false => StatusCode::BAD_GATEWAY,
+        };
+        (code, message.clone())
     } else {
-        eprintln!("unhandled error: {:?}", err);
+        log::error!("Unhandled rejection: {:?}", err);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             "Internal Server Error".to_string(),
@@ -138,3 +1430,68 @@ async fn handle_rejection(
 
     Ok(warp::reply::with_status(message, code))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> &'static super::super::Manifest {
+        let manifest = toml::from_str(
+            r#"
+            [[secrets]]
+            name = "db"
+            destination_path = "/etc/db.conf"
+            owner = "root"
+            group = "root"
+            mode = "0400"
+
+            [[secrets]]
+            name = "tree"
+            destination_path = "/etc/tree/"
+            owner = "root"
+            group = "root"
+            mode = "0400"
+            "#,
+        )
+        .unwrap();
+        Box::leak(Box::new(manifest))
+    }
+
+    fn destination(tail: &str) -> Option<std::path::PathBuf> {
+        resolve_destination(manifest(), tail).and_then(|(_, destination)| destination)
+    }
+
+    #[test]
+    fn resolves_secrets_and_entries() {
+        assert_eq!(destination("db"), Some("/etc/db.conf".into()));
+        assert_eq!(
+            destination("tree/a/b.conf"),
+            Some("/etc/tree/a/b.conf".into())
+        );
+        assert_eq!(destination("tree/a%20b"), Some("/etc/tree/a b".into()));
+    }
+
+    #[test]
+    fn rejects_unknown_secrets_and_misplaced_entries() {
+        assert!(resolve_destination(manifest(), "other").is_none());
+        assert!(resolve_destination(manifest(), "db/entry").is_none());
+        assert!(resolve_destination(manifest(), "tree").is_none());
+    }
+
+    #[test]
+    fn rejects_escaping_the_destination_directory() {
+        for tail in [
+            "tree/..",
+            "tree/a/../../etc/shadow",
+            "tree/%2E%2E/shadow",
+            "tree/.",
+            "tree//etc/shadow",
+            "tree/a%2F..%2F..%2Fshadow",
+            "tree/%2Fetc%2Fshadow",
+            "tree/a%00b",
+            "tree/a/",
+        ] {
+            assert!(resolve_destination(manifest(), tail).is_none(), "{}", tail);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use caps::{CapSet, Capability, CapsHashSet};
+use landlock::{
+    AccessFs, BitFlags, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetStatus, ABI,
+};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+
+// What happens to a syscall outside the allowlist of the network worker.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Violation {
+    #[default]
+    Kill,
+    // Allows the syscall and logs it to the kernel audit log, for debugging the allowlist.
+    Log,
+}
+
+// Syscalls of the network worker after setup: the runtime, the HTTP server, TLS and the socket
+// towards the privileged writer.
+const WORKER_SYSCALLS: &[libc::c_long] = &[
+    // Sockets
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_recvfrom,
+    libc::SYS_sendto,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmsg,
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_close,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_fcntl,
+    // Runtime
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    libc::SYS_eventfd2,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    // Threads of the blocking pool are started on demand
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_tgkill,
+    libc::SYS_restart_syscall,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Memory, including locked buffers for secrets
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_mlock,
+    libc::SYS_munlock,
+];
+
+// Syscalls the network worker is refused with EACCES instead of being killed. The C library opens
+// files lazily, eg; /proc/sys/vm/overcommit_memory when freeing memory, and copes with failure.
+const REFUSED_SYSCALLS: &[libc::c_long] = &[
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    libc::SYS_openat,
+];
+
+// Filesystem accesses confined by Landlock. Newer rights, like connecting to UNIX sockets, stay
+// unrestricted because commands of sinks talk to services through them.
+const CONFINED_ABI: ABI = ABI::V5;
+
+// Where `systemd-creds encrypt` creates the host key on first use.
+const CREDENTIAL_SECRET_DIRECTORY: &str = "/var/lib/systemd";
+
+// The TPM resource manager, `systemd-creds encrypt` seals the key of encrypted credentials with it.
+const TPM_DEVICE: &str = "/dev/tpmrm0";
+
+// Output of commands and hooks is redirected to it.
+const NULL_DEVICE: &str = "/dev/null";
+
+// Directories the privileged side writes to when delivering the manifest, file destinations
+// replace a file inside their parent directory. Commands and hooks only write below the paths
+// they declare.
+pub fn writable_paths(manifest: &super::Manifest) -> Vec<std::path::PathBuf> {
+    let mut paths = std::collections::BTreeSet::new();
+    for secret in manifest.secrets.iter() {
+        let path = match (&secret.sink, &secret.destination_path) {
+            // Directory entries create subdirectories below the destination
+            (super::sink::Sink::File, Some(destination)) if secret.is_tree() => {
+                Some(destination.clone())
+            }
+            (super::sink::Sink::File, Some(destination)) => {
+                destination.parent().map(|parent| parent.to_owned())
+            }
+            (super::sink::Sink::Credential(sink), _) => {
+                if sink.encrypted {
+                    paths.insert(std::path::PathBuf::from(CREDENTIAL_SECRET_DIRECTORY));
+                    // NOTE; Without a TPM the credential is encrypted with the host key only
+                    if std::path::Path::new(TPM_DEVICE).exists() {
+                        paths.insert(std::path::PathBuf::from(TPM_DEVICE));
+                    }
+                }
+                Some(super::credential::directory(sink))
+            }
+            (super::sink::Sink::EnvFile(sink), _) => {
+                sink.path.parent().map(|parent| parent.to_owned())
+            }
+            (super::sink::Sink::Command(sink), _) => {
+                paths.extend(sink.writable_paths.iter().cloned());
+                None
+            }
+            // Keys never touch the filesystem
+            _ => None,
+        };
+        paths.extend(path);
+    }
+    let hooks = manifest
+        .secrets
+        .iter()
+        .flat_map(|secret| secret.on_delivered.iter())
+        .chain(manifest.on_delivered.iter());
+    for hook in hooks {
+        if let super::hook::Hook::Command { writable_paths, .. } = hook {
+            paths.extend(writable_paths.iter().cloned());
+        }
+    }
+
+    paths.into_iter().collect()
+}
+
+// Paths this process and everything it starts may write to, resolved before confining writes so
+// missing paths are refused before the network worker is started.
+pub struct WriteRules(Vec<(std::path::PathBuf, BitFlags<AccessFs>)>);
+
+// Allows writing below `writable`, paths of files allow writing only that file. Removing files is
+// additionally allowed below `removable`, eg; for unlinking the listening socket at exit. Writing
+// /dev/null stays allowed.
+//
+// NOTE; A missing directory is created on delivery, its parent becomes writable instead. Paths
+// missing more than that are refused, the rule would widen towards the root directory.
+pub fn write_rules(
+    writable: &[std::path::PathBuf],
+    removable: Option<&std::path::Path>,
+) -> std::io::Result<WriteRules> {
+    let write = AccessFs::from_write(CONFINED_ABI);
+    let write_file = write & AccessFs::from_file(CONFINED_ABI);
+    let mut rules = Vec::new();
+    for path in writable {
+        let path = existing_path(path)?;
+        let access = match path.is_dir() {
+            true => write,
+            false => write_file,
+        };
+        rules.push((path, access));
+    }
+    if let Some(path) = removable {
+        rules.push((existing_path(path)?, AccessFs::RemoveFile.into()));
+    }
+    rules.push((NULL_DEVICE.into(), AccessFs::WriteFile.into()));
+
+    Ok(WriteRules(rules))
+}
+
+// Restricts this process and everything it starts to the rules.
+pub fn confine_writes(rules: WriteRules) -> std::io::Result<()> {
+    let status = landlock_rules(rules)
+        .map_err(|e| std::io::Error::other(format!("failed to confine writes: {}", e)))?;
+    match status {
+        RulesetStatus::FullyEnforced => {}
+        RulesetStatus::PartiallyEnforced => {
+            log::warn!(
+                "Landlock is partially supported by this kernel, writes are partially confined"
+            )
+        }
+        RulesetStatus::NotEnforced => {
+            log::warn!("Landlock is not supported by this kernel, writes are not confined")
+        }
+    }
+
+    Ok(())
+}
+
+fn landlock_rules(rules: WriteRules) -> Result<RulesetStatus, Box<dyn std::error::Error>> {
+    let mut ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_write(CONFINED_ABI))?
+        .create()?;
+    for (path, access) in rules.0 {
+        ruleset = ruleset.add_rule(PathBeneath::new(PathFd::new(path)?, access))?;
+    }
+
+    Ok(ruleset.restrict_self()?.ruleset)
+}
+
+// The path itself, or its parent when only the path is missing.
+fn existing_path(path: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    // Relative destinations without a directory live in the working directory
+    let path = match path.as_os_str().is_empty() {
+        true => std::env::current_dir()?,
+        false => std::path::absolute(path)?,
+    };
+    if path.exists() {
+        return Ok(path);
+    }
+    match path.parent() {
+        Some(parent) if parent.parent().is_some() && parent.is_dir() => Ok(parent.to_owned()),
+        _ => Err(std::io::Error::other(format!(
+            "'{}' is missing, writes to it can't be confined without widening the rule to an \
+             ancestor, create it first",
+            path.display()
+        ))),
+    }
+}
+
+// Capabilities the privileged side needs for delivering the manifest. Files are written into
+// directories of other users and handed to their owner, commands and keys are handled by children
+// running as their owner.
+pub fn delivery_capabilities(manifest: &super::Manifest) -> CapsHashSet {
+    let mut keep = CapsHashSet::from([
+        Capability::CAP_CHOWN,
+        Capability::CAP_FOWNER,
+        Capability::CAP_DAC_OVERRIDE,
+    ]);
+    for secret in manifest.secrets.iter() {
+        match &secret.sink {
+            super::sink::Sink::Command(_) | super::sink::Sink::Keyring(_) => {
+                keep.extend([Capability::CAP_SETUID, Capability::CAP_SETGID]);
+            }
+            _ => {}
+        }
+    }
+
+    keep
+}
+
+// Removes every capability outside `keep` from the bounding set, so programs started later can't
+// regain it. Without CAP_SETPCAP the bounding set can't change and is left as is.
+pub fn limit_bounding_set(keep: &CapsHashSet) -> std::io::Result<()> {
+    if !caps::has_cap(None, CapSet::Effective, Capability::CAP_SETPCAP).map_err(caps_error)? {
+        return Ok(());
+    }
+
+    let bounding = caps::read(None, CapSet::Bounding).map_err(caps_error)?;
+    for capability in bounding.difference(keep) {
+        caps::drop(None, CapSet::Bounding, *capability).map_err(caps_error)?;
+    }
+
+    Ok(())
+}
+
+// Keeps the capabilities in `keep` this process holds, and drops every other.
+pub fn restrict_capabilities(keep: &CapsHashSet) -> std::io::Result<()> {
+    limit_bounding_set(keep)?;
+    caps::clear(None, CapSet::Ambient).map_err(caps_error)?;
+    caps::clear(None, CapSet::Inheritable).map_err(caps_error)?;
+
+    let permitted = caps::read(None, CapSet::Permitted).map_err(caps_error)?;
+    let retained: CapsHashSet = permitted.intersection(keep).copied().collect();
+    // NOTE; The effective set must stay a subset of the permitted set
+    caps::set(None, CapSet::Effective, &retained).map_err(caps_error)?;
+    caps::set(None, CapSet::Permitted, &retained).map_err(caps_error)?;
+
+    Ok(())
+}
+
+fn caps_error(e: caps::errors::CapsError) -> std::io::Error {
+    std::io::Error::other(format!("failed to change capabilities: {}", e))
+}
+
+// Prevents programs started later from gaining privileges, eg; through setuid binaries or file
+// capabilities.
+pub fn set_no_new_privs() -> std::io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Restricts every thread of this process to the syscalls of the network worker.
+pub fn apply_worker_filter(violation: Violation) -> std::io::Result<()> {
+    let programs = worker_filters(violation)
+        .map_err(|e| std::io::Error::other(format!("failed to build seccomp filter: {}", e)))?;
+    // NOTE; The kernel applies the most restrictive outcome of all installed filters, the
+    // allowlist comes last because installing a filter requires syscalls outside of it
+    for program in programs {
+        seccompiler::apply_filter_all_threads(&program)
+            .map_err(|e| std::io::Error::other(format!("failed to apply seccomp filter: {}", e)))?;
+    }
+
+    Ok(())
+}
+
+fn worker_filters(violation: Violation) -> Result<Vec<BpfProgram>, Box<dyn std::error::Error>> {
+    let argument_equals = |index, value: u64| -> Result<_, Box<dyn std::error::Error>> {
+        let condition =
+            SeccompCondition::new(index, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, value)?;
+        Ok(vec![SeccompRule::new(vec![condition])?])
+    };
+
+    let mut rules: std::collections::BTreeMap<i64, Vec<SeccompRule>> = WORKER_SYSCALLS
+        .iter()
+        .chain(REFUSED_SYSCALLS)
+        .map(|syscall| (*syscall, Vec::new()))
+        .collect();
+    // Sockets are switched to non-blocking mode, every other ioctl is refused
+    rules.insert(libc::SYS_ioctl, argument_equals(1, libc::FIONBIO)?);
+    // Threads of the runtime are named on start
+    rules.insert(
+        libc::SYS_prctl,
+        argument_equals(0, libc::PR_SET_NAME as u64)?,
+    );
+
+    let mismatch = match violation {
+        Violation::Kill => SeccompAction::KillProcess,
+        Violation::Log => SeccompAction::Log,
+    };
+    let arch = std::env::consts::ARCH.try_into()?;
+    let allowlist = SeccompFilter::new(rules, mismatch, SeccompAction::Allow, arch)?;
+
+    let refused = REFUSED_SYSCALLS
+        .iter()
+        .map(|syscall| (*syscall, Vec::new()))
+        .collect();
+    let refusals = SeccompFilter::new(
+        refused,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EACCES as u32),
+        arch,
+    )?;
+
+    Ok(vec![refusals.try_into()?, allowlist.try_into()?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn existing_path_widens_at_most_to_the_parent() {
+        let root = std::env::temp_dir().join(format!("bss-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let existing = existing_path(&root);
+        let missing = existing_path(&root.join("missing"));
+        let missing_parent = existing_path(&root.join("missing/file"));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(existing.unwrap(), root);
+        assert_eq!(missing.unwrap(), root);
+        assert!(missing_parent.is_err());
+        assert!(existing_path(std::path::Path::new("/bss-missing")).is_err());
+    }
+}
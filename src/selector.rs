@@ -0,0 +1,152 @@
+// Target selector attached to a secret in the manifest.
+//
+// Every provided criterion must hold for the selector to match. A criterion holds when any of its
+// listed values matches the guest identity. Empty criteria are ignored.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Selector {
+    #[serde(default)]
+    pub cid: Vec<u32>,
+    #[serde(default)]
+    pub hostname: Vec<String>,
+    #[serde(default)]
+    pub role: Vec<String>,
+}
+
+// The identity of a guest. On the send side this describes the guest at the other end of the
+// connection, on the receive side this describes the guest we're running in.
+#[derive(Debug, Default)]
+pub struct Identity {
+    pub cid: Option<u32>,
+    pub hostname: Option<String>,
+    pub roles: Vec<String>,
+}
+
+impl Selector {
+    pub fn matches(&self, identity: &Identity) -> bool {
+        let cid_matches =
+            self.cid.is_empty() || identity.cid.is_some_and(|cid| self.cid.contains(&cid));
+        let hostname_matches = self.hostname.is_empty()
+            || identity
+                .hostname
+                .as_ref()
+                .is_some_and(|hostname| self.hostname.contains(hostname));
+        let role_matches =
+            self.role.is_empty() || identity.roles.iter().any(|role| self.role.contains(role));
+
+        cid_matches && hostname_matches && role_matches
+    }
+}
+
+impl Identity {
+    // Detects the identity of the local machine. Values explicitly provided on the command line
+    // take precedence over the detected ones.
+    pub fn detect_local() -> Self {
+        Identity {
+            cid: local_cid(),
+            hostname: local_hostname(),
+            roles: Vec::new(),
+        }
+    }
+}
+
+fn local_hostname() -> Option<String> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    let hostname = hostname.trim();
+    if hostname.is_empty() {
+        return None;
+    }
+
+    Some(hostname.to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn local_cid() -> Option<u32> {
+    use std::os::fd::AsRawFd;
+
+    // REF; linux/vm_sockets.h
+    const IOCTL_VM_SOCKETS_GET_LOCAL_CID: u32 = 0x7b9;
+    // The CID reported when there is no hypervisor transport available.
+    const VMADDR_CID_ANY: u32 = u32::MAX;
+
+    let vsock_device = std::fs::File::open("/dev/vsock").ok()?;
+    let mut cid: u32 = VMADDR_CID_ANY;
+    let result = unsafe {
+        libc::ioctl(
+            vsock_device.as_raw_fd(),
+            IOCTL_VM_SOCKETS_GET_LOCAL_CID as _,
+            &mut cid as *mut u32,
+        )
+    };
+
+    match (result, cid) {
+        (0, VMADDR_CID_ANY) => None,
+        (0, cid) => Some(cid),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn local_cid() -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guest() -> Identity {
+        Identity {
+            cid: Some(3),
+            hostname: Some("db1".to_string()),
+            roles: vec!["db".to_string(), "backup".to_string()],
+        }
+    }
+
+    #[test]
+    fn empty_selector_matches_every_guest() {
+        assert!(Selector::default().matches(&guest()));
+        assert!(Selector::default().matches(&Identity::default()));
+    }
+
+    #[test]
+    fn criterion_holds_when_any_value_matches() {
+        let selector = Selector {
+            cid: vec![2, 3],
+            role: vec!["web".to_string(), "backup".to_string()],
+            ..Default::default()
+        };
+        assert!(selector.matches(&guest()));
+    }
+
+    #[test]
+    fn every_criterion_must_hold() {
+        let selector = Selector {
+            cid: vec![3],
+            hostname: vec!["db2".to_string()],
+            ..Default::default()
+        };
+        assert!(!selector.matches(&guest()));
+    }
+
+    #[test]
+    fn unknown_identity_fails_criteria() {
+        let selector = Selector {
+            cid: vec![3],
+            ..Default::default()
+        };
+        assert!(!selector.matches(&Identity::default()));
+
+        let selector = Selector {
+            hostname: vec!["db1".to_string()],
+            ..Default::default()
+        };
+        assert!(!selector.matches(&Identity::default()));
+
+        let selector = Selector {
+            role: vec!["db".to_string()],
+            ..Default::default()
+        };
+        assert!(!selector.matches(&Identity::default()));
+    }
+}
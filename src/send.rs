@@ -5,9 +5,42 @@ pub fn client_main(
     use lexopt::prelude::*;
 
     let mut manifest_path = None;
+    // The sender has no way to discover the guest at the other end, its identity is exactly
+    // what's provided on the command line.
+    let mut guest = super::selector::Identity::default();
+    let mut token = None;
+    let mut hmac = false;
+    let mut server_name = super::tls::DEFAULT_SERVER_NAME.to_string();
+    let mut audit_path = None;
+    let mut dry_run = false;
 
     while let Some(arg) = parser.next()? {
         match arg {
+            Long("cid") => {
+                guest.cid = Some(parser.value()?.parse()?);
+            }
+            Long("hostname") => {
+                guest.hostname = Some(parser.value()?.string()?);
+            }
+            Long("role") => {
+                guest.roles.push(parser.value()?.string()?);
+            }
+            Long("token-file") => {
+                let path: std::path::PathBuf = parser.value()?.into();
+                token = Some(super::token::read_token_file(&path)?);
+            }
+            Long("hmac") => {
+                hmac = true;
+            }
+            Long("tls-server-name") => {
+                server_name = parser.value()?.string()?;
+            }
+            Long("dry-run") => {
+                dry_run = true;
+            }
+            Long("audit-log") => {
+                audit_path = Some(std::path::PathBuf::from(parser.value()?));
+            }
             Value(value) if manifest_path.is_none() => {
                 manifest_path = Some(value.into());
             }
@@ -23,68 +56,471 @@ pub fn client_main(
         }
     };
 
-    let manifest: &'static _ = super::read_and_deserialize_manifest(manifest_path);
+    let manifest: &'static _ = super::read_and_deserialize_manifest(manifest_path, &guest)?;
+
+    let mut stdin_consumer = None;
+    let mut transfers = Vec::new();
+    for secret in manifest.secrets.iter() {
+        let source = match (&secret.source, stdin_consumer) {
+            (None, _) => {
+                return Err(format!("secret '{}' has no source", secret.name).into());
+            }
+            (Some(super::source::Source::Stdin), Some(other)) => {
+                return Err(format!(
+                    "secrets '{}' and '{}' both read from stdin",
+                    other, secret.name
+                )
+                .into());
+            }
+            (Some(source @ super::source::Source::Stdin), None) => {
+                stdin_consumer = Some(&secret.name);
+                source
+            }
+            (Some(source), _) => source,
+        };
+
+        let recipient = match &secret.recipient {
+            Some(recipient) => Some(super::encryption::parse_recipient(recipient)?),
+            None => None,
+        };
+
+        if !source.is_tree() {
+            transfers.push(Transfer {
+                name: secret.name.clone(),
+                secret: &secret.name,
+                source: source.clone(),
+                recipient,
+                destination: secret
+                    .sink
+                    .describe_destination(&secret.name, secret.destination_path.as_deref()),
+            });
+            continue;
+        }
 
-    // TODO Create connect object based on inputs
-    run_client(&settings, manifest)
+        for (relative, path) in source.expand()? {
+            let destination_path = secret
+                .destination_path
+                .as_ref()
+                .map(|directory| directory.join(&relative));
+            transfers.push(Transfer {
+                name: format!("{}/{}", secret.name, relative),
+                secret: &secret.name,
+                source: super::source::Source::File { path },
+                recipient: recipient.clone(),
+                destination: secret
+                    .sink
+                    .describe_destination(&secret.name, destination_path.as_deref()),
+            });
+        }
+    }
+
+    if hmac && token.is_none() {
+        return Err("--hmac requires a pre-shared token".into());
+    }
+
+    if dry_run {
+        return plan_transfers(&settings, transfers);
+    }
+
+    let client_settings = ClientSettings {
+        address: super::transport::Address::from_settings(&settings)?,
+        tls: super::tls::Connector::new(&settings.tls, &server_name)?,
+        token,
+        hmac,
+        audit: audit_path
+            .map(|path| super::audit::AuditLog::open(&path, super::audit::Side::Send))
+            .transpose()?,
+    };
+    let client_settings: &'static _ = Box::leak(Box::new(client_settings));
+    run_client(&settings, client_settings, transfers)
 }
 
-async fn secret_push_operation(
-    secret: &super::Secret,
+// Opens every source and checks the size of the request body, without connecting.
+#[tokio::main(flavor = "current_thread")]
+async fn plan_transfers(
+    settings: &super::GlobalSettings,
+    transfers: Vec<Transfer>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use super::source::Source;
+
+    let mut entries = Vec::new();
+    for transfer in transfers {
+        // Generating would persist a new value, the plan covers content that's generated anew
+        let (source, generated) = match &transfer.source {
+            Source::Generate {
+                generator,
+                persist_path,
+            } if !persist_path.as_ref().is_some_and(|path| path.exists()) => (
+                Source::Generate {
+                    generator: generator.clone(),
+                    persist_path: None,
+                },
+                true,
+            ),
+            source => (source.clone(), false),
+        };
+
+        let mut entry = super::plan::Entry {
+            detail: super::plan::Transfer {
+                secret: transfer.name.clone(),
+                source: transfer.source.kind(),
+                destination: transfer.destination.clone(),
+                size: None,
+                digest: None,
+                encrypted: transfer.recipient.is_some(),
+                body_size: None,
+            },
+            problems: Vec::new(),
+        };
+        if let Err(e) = plan_transfer(&source, &transfer, generated, &mut entry.detail).await {
+            entry.problems.push(e.to_string());
+        }
+        if let Some(body_size) = entry.detail.body_size {
+            if body_size > settings.max_transmission_bytes.into() {
+                entry.problems.push(format!(
+                    "the body of {} bytes exceeds the limit of {} bytes",
+                    body_size, settings.max_transmission_bytes
+                ));
+            }
+        }
+        entries.push(entry);
+    }
+
+    super::plan::print(super::audit::Side::Send, &entries)
+}
+
+async fn plan_transfer(
+    source: &super::source::Source,
+    transfer: &Transfer,
+    generated: bool,
+    planned: &mut super::plan::Transfer,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    use futures_util::TryStreamExt;
-    use http_body_util::{BodyExt, StreamBody};
-    use hyper::body::Frame;
-    use hyper::Request;
-    use tokio::fs::File;
-    use tokio_util::io::ReaderStream;
+    let mut payload = source.open().await?;
+    let content = payload.content().await?;
+    planned.size = Some(content.size());
+    planned.digest = (!generated).then(|| content.digest());
+    planned.body_size = Some(match &transfer.recipient {
+        Some(recipient) => {
+            let plaintext = payload.into_memory().await?;
+            super::encryption::encrypt(recipient, &plaintext)?.len() as u64
+        }
+        None => payload.len(),
+    });
+    Ok(())
+}
+
+// Settings specific to the send side.
+struct ClientSettings {
+    // Where the receiver listens.
+    address: super::transport::Address,
+    // Wraps every connection in mutual TLS.
+    tls: Option<super::tls::Connector>,
+    // Pre-shared token presented on every request.
+    token: Option<super::memory::SecretBuffer>,
+    // Signs every request with the token instead of presenting it.
+    hmac: bool,
+    // Records every transfer.
+    audit: Option<super::audit::AuditLog>,
+}
+
+impl ClientSettings {
+    // Adds the credentials to the request. Signed requests cover the provided content and use a
+    // fresh nonce requested over the same connection.
+    async fn authorize(
+        &self,
+        sender: &mut RequestSender,
+        mut request: hyper::http::request::Builder,
+        content: Option<&[u8]>,
+    ) -> Result<hyper::http::request::Builder, Box<dyn std::error::Error + Send + Sync>> {
+        use super::signature;
 
-    if cfg!(not(unix)) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Must run under Unix-like platform!",
+        let token = match &self.token {
+            Some(token) => token,
+            None => return Ok(request),
+        };
+
+        if !self.hmac {
+            let value = zeroize::Zeroizing::new([b"Bearer ", token.as_slice()].concat());
+            let mut value = hyper::header::HeaderValue::from_bytes(&value)?;
+            value.set_sensitive(true);
+            return Ok(request.header(hyper::header::AUTHORIZATION, value));
+        }
+
+        let content = content.ok_or("signed requests require the content in memory")?;
+        let content_sha256 = hex::encode(signature::content_sha256(content));
+        let timestamp = signature::unix_timestamp().to_string();
+        let nonce = request_nonce(sender).await?;
+        let method = request
+            .method_ref()
+            .ok_or("request without method")?
+            .clone();
+        let path = request
+            .uri_ref()
+            .ok_or("request without path")?
+            .path()
+            .to_string();
+        let request_signature = signature::sign(
+            token,
+            method.as_str(),
+            &path,
+            &content_sha256,
+            &timestamp,
+            &nonce,
+        );
+
+        request = request
+            .header(signature::TIMESTAMP_HEADER, timestamp)
+            .header(signature::NONCE_HEADER, nonce)
+            .header(signature::CONTENT_SHA256_HEADER, content_sha256)
+            .header(signature::SIGNATURE_HEADER, request_signature);
+        Ok(request)
+    }
+}
+
+// Retrieves a single-use nonce from the receiver, required for signing a request.
+async fn request_nonce(
+    sender: &mut RequestSender,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    use http_body_util::{BodyExt, Empty};
+
+    let request = hyper::Request::get("/session/nonce")
+        .body(Empty::new().map_err(|never| match never {}).boxed())?;
+    let response = sender.send_request(request).await?;
+    if response.status() != hyper::StatusCode::OK {
+        return Err(format!(
+            "receiver refused a nonce with status {}",
+            response.status()
         ))?;
     }
 
-    let unix_socket_stream = super::unix_socket::connect_unix_sock_stream().await?;
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(unix_socket_stream).await?;
+    let nonce = response.into_body().collect().await?.to_bytes();
+    Ok(String::from_utf8(nonce.to_vec())?)
+}
+
+// Characters that are escaped within a single segment of the request path.
+const PATH_SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_');
+
+// A single upload. Secrets with a directory destination expand into one transfer per file.
+struct Transfer {
+    name: String,
+    // The name of the secret in the manifest, without the path of directory entries.
+    secret: &'static str,
+    source: super::source::Source,
+    // Encrypts the content to the guest before it leaves the sender.
+    recipient: Option<age::x25519::Recipient>,
+    // Where the receiver delivers the content, for the audit log.
+    destination: String,
+}
+
+type RequestSender = hyper::client::conn::http1::SendRequest<
+    http_body_util::combinators::BoxBody<hyper::body::Bytes, std::io::Error>,
+>;
+
+// Opens a new connection to the receiver.
+async fn connect(
+    client_settings: &ClientSettings,
+) -> Result<RequestSender, Box<dyn std::error::Error + Send + Sync>> {
+    let address = &client_settings.address;
+    let mut connection = address
+        .connect()
+        .await
+        .map_err(|e| format!("failed to connect to {}: {}", address, e))?;
+    if let Some(connector) = &client_settings.tls {
+        connection = connector
+            .connect(connection)
+            .await
+            .map_err(|e| format!("TLS handshake with {} failed: {}", address, e))?;
+    }
+    let (sender, conn) =
+        hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(connection)).await?;
 
     tokio::task::spawn(async move {
         if let Err(err) = conn.await {
-            println!("Connection failed: {:?}", err);
+            log::error!(error:% = err; "Connection failed");
         }
     });
 
-    let file = File::open(&secret.source_path).await?;
-    let file_length = file.metadata().await?.len();
-    let file_reader = ReaderStream::new(file);
-    // Convert to http_body_util::BoxBody
-    let stream_body = StreamBody::new(file_reader.map_ok(Frame::data));
-    let boxed_body = stream_body.boxed();
+    Ok(sender)
+}
+
+async fn secret_push_operation(
+    client_settings: &'static ClientSettings,
+    transfer: Transfer,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut payload = transfer.source.open().await?;
+    let Some(audit) = &client_settings.audit else {
+        return push(client_settings, &transfer, payload).await;
+    };
+
+    // The digest covers the plaintext, like the entries of the receiver
+    let content = payload.content().await?;
+    let result = push(client_settings, &transfer, payload).await;
+    audit.record(super::audit::Transfer {
+        peer: client_settings.address.to_string(),
+        secret: transfer.secret.to_string(),
+        digest: result.is_ok().then(|| content.digest()),
+        size: content.size(),
+        destination: transfer.destination.clone(),
+        outcome: match &result {
+            Ok(()) => "delivered",
+            Err(_) => "failed",
+        }
+        .to_string(),
+    });
+    result
+}
+
+// Sends the content of a single transfer.
+async fn push(
+    client_settings: &ClientSettings,
+    transfer: &Transfer,
+    mut payload: super::source::Payload,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use hyper::Request;
+
+    if let Some(recipient) = &transfer.recipient {
+        let plaintext = payload.into_memory().await?;
+        let ciphertext = super::encryption::encrypt(recipient, &plaintext)?;
+        payload = super::source::Payload::Memory(ciphertext.try_into()?);
+    }
+    if client_settings.hmac && payload.as_memory().is_none() {
+        // The digest of the content is signed before sending it
+        payload = super::source::Payload::Memory(payload.into_memory().await?);
+    }
+    let payload_length = payload.len();
+
+    let mut sender = connect(client_settings).await?;
 
-    let request = Request::post("/secrets/test")
+    let request_path: String = transfer
+        .name
+        .split('/')
+        .map(|segment| {
+            format!(
+                "/{}",
+                percent_encoding::utf8_percent_encode(segment, PATH_SEGMENT)
+            )
+        })
+        .collect();
+    let mut request = Request::post(format!("/secrets{}", request_path));
+    if transfer.recipient.is_some() {
+        request = request.header(super::encryption::ENCRYPTION_HEADER, super::encryption::AGE);
+    }
+    let request = client_settings
+        .authorize(&mut sender, request, payload.as_memory())
+        .await?
         // Length is required by the server, otherwise it terminates our connection early
-        .header(hyper::header::CONTENT_LENGTH, file_length)
-        .body(boxed_body)?;
+        .header(hyper::header::CONTENT_LENGTH, payload_length)
+        .body(payload.into_body())?;
 
     let response = sender.send_request(request).await?;
-    assert!(response.status() == hyper::StatusCode::CREATED);
+    if response.status() != hyper::StatusCode::CREATED {
+        use http_body_util::BodyExt;
 
+        // NOTE; A receiver selecting a different subset of the manifest answers with NOT_FOUND
+        let status = response.status();
+        let message = response.into_body().collect().await?.to_bytes();
+        return Err(format!(
+            "receiver refused secret '{}' with status {}: {}",
+            transfer.name,
+            status,
+            String::from_utf8_lossy(&message)
+        ))?;
+    }
+
+    log::info!(
+        secret = transfer.name.as_str(), peer:% = client_settings.address, bytes = payload_length,
+        outcome = "delivered";
+        "Transfer delivered"
+    );
     Ok(())
 }
 
+// Transfers open a connection each, directory sources can expand into many.
+const MAX_CONCURRENT_TRANSFERS: usize = 8;
+
 #[tokio::main(flavor = "current_thread")]
 async fn run_client(
-    settings: &super::GlobalSettings,
-    manifest: &'static super::Manifest,
+    _settings: &super::GlobalSettings,
+    client_settings: &'static ClientSettings,
+    transfers: Vec<Transfer>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut join_set = tokio::task::JoinSet::new();
-    for secret in manifest.secrets.iter() {
-        join_set.spawn(secret_push_operation(secret));
+    use futures_util::StreamExt;
+
+    let mut completion = super::session::Completion::default();
+    for transfer in transfers.iter().filter(|t| t.name != t.secret) {
+        *completion
+            .entries
+            .entry(transfer.secret.to_string())
+            .or_default() += 1;
+    }
+
+    let mut results = futures_util::stream::iter(transfers)
+        .map(|transfer| async move {
+            let name = transfer.name.clone();
+            (name, secret_push_operation(client_settings, transfer).await)
+        })
+        .buffer_unordered(MAX_CONCURRENT_TRANSFERS);
+
+    let mut failures = 0;
+    while let Some(result) = results.next().await {
+        if let (name, Err(e)) = result {
+            log::error!(
+                secret = name.as_str(), peer:% = client_settings.address, outcome = "failed",
+                error:% = e;
+                "Transfer failed"
+            );
+            failures += 1;
+        }
     }
 
-    while let Some(job_result) = join_set.join_next().await {
-        let _ = job_result?;
+    // NOTE; The session stays open on failure, so the receiver accepts another attempt
+    if failures > 0 {
+        return Err(format!("{} transfer(s) failed", failures).into());
+    }
+
+    if let Err(e) = complete_session(client_settings, &completion).await {
+        return Err(e.to_string().into());
+    }
+
+    Ok(())
+}
+
+// Tells the receiver all secrets are sent, which runs its post-delivery hooks. The completion lists
+// the directory entries sent, so the receiver notices entries that never arrived.
+async fn complete_session(
+    client_settings: &ClientSettings,
+    completion: &super::session::Completion,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use http_body_util::{BodyExt, Full};
+
+    let body = serde_json::to_vec(completion)?;
+    let mut sender = connect(client_settings).await?;
+    let request = client_settings
+        .authorize(
+            &mut sender,
+            hyper::Request::post("/session/complete")
+                .header(hyper::header::CONTENT_TYPE, "application/json"),
+            Some(&body),
+        )
+        .await?
+        .body(
+            Full::new(hyper::body::Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed(),
+        )?;
+
+    let response = sender.send_request(request).await?;
+    if response.status() != hyper::StatusCode::OK {
+        let status = response.status();
+        let report = response.into_body().collect().await?.to_bytes();
+        return Err(format!(
+            "receiver completed the session with status {}: {}",
+            status,
+            String::from_utf8_lossy(&report)
+        ))?;
     }
 
     Ok(())
@@ -0,0 +1,330 @@
+// Tracks the deliveries of a single run of the receiver. The sender completes the session after
+// all of its uploads succeeded, which runs the post-delivery hooks.
+//
+// Directory entries are tracked one by one, a directory is delivered when every entry the sender
+// planned arrived and none of them failed.
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretState {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SecretReport {
+    pub name: String,
+    pub state: SecretState,
+    // Amount of delivered uploads, directory entries receive one upload per file.
+    pub uploads: u32,
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct HookReport {
+    // Unset for hooks of the session.
+    pub secret: Option<String>,
+    pub hook: String,
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Report {
+    pub completed: bool,
+    pub success: bool,
+    pub secrets: Vec<SecretReport>,
+    pub hooks: Vec<HookReport>,
+}
+
+// What the sender transferred, sent along with the completion.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+pub struct Completion {
+    // Amount of directory entries per secret, secrets without entries are missing.
+    #[serde(default)]
+    pub entries: BTreeMap<String, u32>,
+}
+
+pub struct Session {
+    secrets: Vec<SecretReport>,
+    // The last outcome of every upload by secret and upload path, failures keep their error.
+    outcomes: HashMap<String, BTreeMap<String, Option<String>>>,
+    hooks: Vec<HookReport>,
+    completed: bool,
+    // Set while the hooks run, closed when the first completion finishes.
+    completing: Option<tokio::sync::watch::Receiver<()>>,
+}
+
+impl Session {
+    pub fn new(manifest: &super::Manifest) -> Self {
+        Session {
+            secrets: manifest
+                .secrets
+                .iter()
+                .map(|secret| SecretReport {
+                    name: secret.name.clone(),
+                    state: SecretState::Pending,
+                    uploads: 0,
+                    error: None,
+                })
+                .collect(),
+            outcomes: HashMap::new(),
+            hooks: Vec::new(),
+            completed: false,
+            completing: None,
+        }
+    }
+
+    // Records the outcome of an upload to the path below /secrets, eg; "certs/ca.pem" for a
+    // directory entry. Uploading the path again replaces its outcome, failures of others stay.
+    pub fn record_delivery(&mut self, name: &str, path: &str, outcome: Result<(), String>) {
+        let Some(secret) = self.secrets.iter_mut().find(|secret| secret.name == name) else {
+            return;
+        };
+        if outcome.is_ok() {
+            secret.uploads += 1;
+        }
+
+        let outcomes = self.outcomes.entry(name.to_string()).or_default();
+        outcomes.insert(path.to_string(), outcome.err());
+        let failure = outcomes
+            .iter()
+            .find_map(|(path, error)| Some(format!("{}: {}", path, error.as_ref()?)));
+        (secret.state, secret.error) = match failure {
+            Some(e) => (SecretState::Failed, Some(e)),
+            None => (SecretState::Delivered, None),
+        };
+    }
+
+    // Fails directories missing entries the sender planned.
+    fn check_entries(&mut self, completion: &Completion) {
+        for secret in self.secrets.iter_mut() {
+            let Some(planned) = completion.entries.get(&secret.name) else {
+                continue;
+            };
+            let delivered = self.outcomes.get(&secret.name).map_or(0, |outcomes| {
+                outcomes.values().filter(|error| error.is_none()).count()
+            });
+            if secret.state != SecretState::Failed && delivered < *planned as usize {
+                secret.state = SecretState::Failed;
+                secret.error = Some(format!("{} of {} entries delivered", delivered, planned));
+            }
+        }
+    }
+
+    // Progress for the status line of the service manager, eg; "3/5 secrets received".
+    pub fn status(&self) -> String {
+        let delivered = self
+            .secrets
+            .iter()
+            .filter(|secret| secret.state == SecretState::Delivered)
+            .count();
+        let progress = format!("{}/{} secrets received", delivered, self.secrets.len());
+        match (self.completed, self.report().success) {
+            (false, _) => progress,
+            (true, true) => format!("{}, session completed", progress),
+            (true, false) => format!("{}, session completed with failures", progress),
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let delivered = self
+            .secrets
+            .iter()
+            .all(|secret| secret.state == SecretState::Delivered);
+        let hooks_succeeded = self.hooks.iter().all(|hook| hook.error.is_none());
+
+        Report {
+            completed: self.completed,
+            success: self.completed && delivered && hooks_succeeded,
+            secrets: self.secrets.clone(),
+            hooks: self.hooks.clone(),
+        }
+    }
+}
+
+// Runs the hooks of every delivered secret, followed by the hooks of the session, and marks the
+// session completed. Completing a session again only reports the earlier outcome, concurrent
+// completions wait for the first one. Directories missing entries of the completion fail.
+//
+// Hooks receive the environment variables;
+//   BSS_CHANGED_SECRETS   Space separated names of the delivered secrets.
+//   BSS_SECRET_NAME       Name of the secret, only for hooks of a secret.
+pub async fn complete(
+    manifest: &super::Manifest,
+    session: &std::sync::Mutex<Session>,
+    completion: &Completion,
+) -> Report {
+    let (delivered, completing): (Vec<String>, _) = loop {
+        let mut waiting = {
+            let mut session = session.lock().expect("session lock poisoned");
+            if session.completed {
+                return session.report();
+            }
+
+            // NOTE; A completion that's cancelled, eg; by the sender disconnecting, closes the
+            // channel without completing. The next caller takes over.
+            match session
+                .completing
+                .as_ref()
+                .filter(|completing| completing.has_changed().is_ok())
+            {
+                Some(completing) => completing.clone(),
+                None => {
+                    let (sender, receiver) = tokio::sync::watch::channel(());
+                    session.completing = Some(receiver);
+                    session.check_entries(completion);
+                    let delivered = session
+                        .secrets
+                        .iter()
+                        .filter(|secret| secret.state == SecretState::Delivered)
+                        .map(|secret| secret.name.clone())
+                        .collect();
+                    break (delivered, sender);
+                }
+            }
+        };
+        // Returns once the running completion finishes or is cancelled
+        let _ = waiting.changed().await;
+    };
+
+    let changed_secrets = delivered.join(" ");
+    let mut hook_reports = Vec::new();
+    for secret in manifest
+        .secrets
+        .iter()
+        .filter(|secret| delivered.contains(&secret.name))
+    {
+        let environment = [
+            ("BSS_CHANGED_SECRETS", changed_secrets.clone()),
+            ("BSS_SECRET_NAME", secret.name.clone()),
+        ];
+        for hook in secret.on_delivered.iter() {
+            hook_reports.push(HookReport {
+                secret: Some(secret.name.clone()),
+                hook: hook.to_string(),
+                error: hook.run(&environment).await.err(),
+            });
+        }
+    }
+
+    if !delivered.is_empty() {
+        let environment = [("BSS_CHANGED_SECRETS", changed_secrets.clone())];
+        for hook in manifest.on_delivered.iter() {
+            hook_reports.push(HookReport {
+                secret: None,
+                hook: hook.to_string(),
+                error: hook.run(&environment).await.err(),
+            });
+        }
+    }
+
+    for hook_report in hook_reports.iter() {
+        if let Some(e) = &hook_report.error {
+            log::error!(
+                secret = hook_report.secret.as_deref(), hook = hook_report.hook.as_str(), error = e.as_str();
+                "Hook failed"
+            );
+        }
+    }
+
+    let mut session = session.lock().expect("session lock poisoned");
+    session.hooks = hook_reports;
+    session.completed = true;
+    drop(completing);
+    session.report()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_completions_run_hooks_once() {
+        let path = std::env::temp_dir().join(format!("bss-hooks-{}", std::process::id()));
+        let manifest: super::super::Manifest = toml::from_str(&format!(
+            r#"
+            on_delivered = [{{ type = "command", argv = ["sh", "-c", "sleep 0.2; echo run >> {}"] }}]
+
+            [[secrets]]
+            name = "db"
+            destination_path = "/etc/db.conf"
+            owner = "root"
+            group = "root"
+            mode = "0400"
+            "#,
+            path.display()
+        ))
+        .unwrap();
+        let session = std::sync::Mutex::new(Session::new(&manifest));
+        session.lock().unwrap().record_delivery("db", "db", Ok(()));
+
+        let completion = Completion::default();
+        let (first, second) = tokio::join!(
+            complete(&manifest, &session, &completion),
+            complete(&manifest, &session, &completion)
+        );
+        let runs = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(runs, "run\n");
+        assert!(first.completed && first.success);
+        assert!(second.completed && second.success);
+        assert_eq!(second.hooks.len(), 1);
+    }
+
+    fn tree() -> super::super::Manifest {
+        toml::from_str(
+            r#"
+            [[secrets]]
+            name = "certs"
+            destination_path = "/etc/certs/"
+            owner = "root"
+            group = "root"
+            mode = "0400"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn failed_entries_stay_failed() {
+        let mut session = Session::new(&tree());
+        session.record_delivery("certs", "certs/a", Err("sink failed".into()));
+        session.record_delivery("certs", "certs/b", Ok(()));
+
+        let report = session.report();
+        assert_eq!(report.secrets[0].state, SecretState::Failed);
+        assert_eq!(
+            report.secrets[0].error.as_deref(),
+            Some("certs/a: sink failed")
+        );
+
+        // Uploading the failed entry again recovers it
+        session.record_delivery("certs", "certs/a", Ok(()));
+        assert_eq!(session.report().secrets[0].state, SecretState::Delivered);
+    }
+
+    #[tokio::test]
+    async fn missing_entries_fail_completion() {
+        let manifest = tree();
+        let session = std::sync::Mutex::new(Session::new(&manifest));
+        session
+            .lock()
+            .unwrap()
+            .record_delivery("certs", "certs/a", Ok(()));
+
+        let completion = Completion {
+            entries: BTreeMap::from([("certs".to_string(), 2)]),
+        };
+        let report = complete(&manifest, &session, &completion).await;
+
+        assert!(report.completed && !report.success);
+        assert_eq!(report.secrets[0].state, SecretState::Failed);
+        assert_eq!(
+            report.secrets[0].error.as_deref(),
+            Some("1 of 2 entries delivered")
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::transport::ConnectionId;
+
+// HMAC request signing. Each request carries a timestamp, a nonce handed out by the receiver and
+// the digest of its body, all covered by a signature keyed with the pre-shared token. The receiver
+// rejects stale timestamps and nonces it didn't issue or already saw, which prevents replaying an
+// observed request. Nonces are bound to the connection they're issued on.
+
+pub const TIMESTAMP_HEADER: &str = "x-bss-timestamp";
+pub const NONCE_HEADER: &str = "x-bss-nonce";
+pub const CONTENT_SHA256_HEADER: &str = "x-bss-content-sha256";
+pub const SIGNATURE_HEADER: &str = "x-bss-signature";
+
+// Maximum difference between the clocks of sender and receiver, also the lifetime of a nonce.
+pub const MAX_CLOCK_SKEW_SECONDS: u64 = 300;
+
+// Upper bound on the amount of unused nonces, protects the receiver memory. The oldest nonce is
+// evicted beyond it.
+const MAX_OUTSTANDING_NONCES: usize = 4096;
+
+pub fn content_sha256(content: &[u8]) -> [u8; 32] {
+    Sha256::digest(content).into()
+}
+
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn mac(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    content_sha256: &str,
+    timestamp: &str,
+    nonce: &str,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in [method, path, content_sha256, timestamp, nonce] {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+    mac
+}
+
+// Returns the hex encoded signature over the request.
+pub fn sign(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    content_sha256: &str,
+    timestamp: &str,
+    nonce: &str,
+) -> String {
+    let mac = mac(key, method, path, content_sha256, timestamp, nonce);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Verifies the hex encoded signature in constant time.
+pub fn verify(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    content_sha256: &str,
+    timestamp: &str,
+    nonce: &str,
+    signature: &str,
+) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    mac(key, method, path, content_sha256, timestamp, nonce)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+// Returns true if the timestamp is within the allowed clock skew.
+pub fn is_fresh(timestamp: &str) -> bool {
+    match timestamp.parse::<u64>() {
+        Ok(timestamp) => unix_timestamp().abs_diff(timestamp) <= MAX_CLOCK_SKEW_SECONDS,
+        Err(_) => false,
+    }
+}
+
+// Nonces handed out by the receiver. Each connection holds at most one nonce, which is accepted
+// exactly once on that connection. Requesting nonces on other connections can't exhaust them for
+// the sender.
+#[derive(Default)]
+pub struct NonceStore {
+    issued: std::sync::Mutex<std::collections::HashMap<ConnectionId, (String, std::time::Instant)>>,
+}
+
+impl NonceStore {
+    // Issues a nonce for the connection, replacing the unused nonce it was issued before.
+    pub fn issue(&self, connection: ConnectionId) -> Result<String, getrandom::Error> {
+        let mut nonce = [0u8; 16];
+        getrandom::getrandom(&mut nonce)?;
+        let nonce = hex::encode(nonce);
+
+        let mut issued = self.issued.lock().expect("nonce lock poisoned");
+        if issued.len() >= MAX_OUTSTANDING_NONCES && !issued.contains_key(&connection) {
+            let lifetime = std::time::Duration::from_secs(MAX_CLOCK_SKEW_SECONDS);
+            issued.retain(|_, (_, issued_at)| issued_at.elapsed() <= lifetime);
+        }
+        if issued.len() >= MAX_OUTSTANDING_NONCES && !issued.contains_key(&connection) {
+            let oldest = issued
+                .iter()
+                .min_by_key(|(_, (_, issued_at))| *issued_at)
+                .map(|(connection, _)| *connection);
+            if let Some(oldest) = oldest {
+                issued.remove(&oldest);
+            }
+        }
+
+        issued.insert(connection, (nonce.clone(), std::time::Instant::now()));
+        Ok(nonce)
+    }
+
+    // Returns true if the nonce was issued on the connection, not yet used and not expired.
+    pub fn consume(&self, connection: ConnectionId, nonce: &str) -> bool {
+        let lifetime = std::time::Duration::from_secs(MAX_CLOCK_SKEW_SECONDS);
+        let mut issued = self.issued.lock().expect("nonce lock poisoned");
+        match issued.get(&connection) {
+            Some((issued_nonce, _)) if issued_nonce == nonce => {}
+            _ => return false,
+        }
+        issued
+            .remove(&connection)
+            .is_some_and(|(_, issued_at)| issued_at.elapsed() <= lifetime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"pre-shared token";
+    const DIGEST: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn signature() -> String {
+        sign(KEY, "POST", "/secrets/db", DIGEST, "1700000000", "00ff")
+    }
+
+    #[test]
+    fn verifies_own_signature() {
+        assert!(verify(
+            KEY,
+            "POST",
+            "/secrets/db",
+            DIGEST,
+            "1700000000",
+            "00ff",
+            &signature()
+        ));
+    }
+
+    #[test]
+    fn rejects_changed_requests() {
+        let signature = signature();
+        assert!(!verify(
+            b"other token",
+            "POST",
+            "/secrets/db",
+            DIGEST,
+            "1700000000",
+            "00ff",
+            &signature
+        ));
+        assert!(!verify(
+            KEY,
+            "PUT",
+            "/secrets/db",
+            DIGEST,
+            "1700000000",
+            "00ff",
+            &signature
+        ));
+        assert!(!verify(
+            KEY,
+            "POST",
+            "/secrets/dc",
+            DIGEST,
+            "1700000000",
+            "00ff",
+            &signature
+        ));
+        assert!(!verify(
+            KEY,
+            "POST",
+            "/secrets/db",
+            &DIGEST.replace('e', "f"),
+            "1700000000",
+            "00ff",
+            &signature
+        ));
+        assert!(!verify(
+            KEY,
+            "POST",
+            "/secrets/db",
+            DIGEST,
+            "1700000001",
+            "00ff",
+            &signature
+        ));
+        assert!(!verify(
+            KEY,
+            "POST",
+            "/secrets/db",
+            DIGEST,
+            "1700000000",
+            "00fe",
+            &signature
+        ));
+    }
+
+    #[test]
+    fn fields_cannot_shift_across_separators() {
+        let signature = sign(KEY, "POST", "/a", DIGEST, "1", "2");
+        assert!(!verify(KEY, "POST", "/a\n", DIGEST, "1", "2", &signature));
+        assert!(!verify(KEY, "POST", "/a", DIGEST, "12", "", &signature));
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let signature = signature();
+        assert!(!verify(
+            KEY,
+            "POST",
+            "/secrets/db",
+            DIGEST,
+            "1700000000",
+            "00ff",
+            "not hex"
+        ));
+        assert!(!verify(
+            KEY,
+            "POST",
+            "/secrets/db",
+            DIGEST,
+            "1700000000",
+            "00ff",
+            &signature[..32]
+        ));
+        assert!(!verify(
+            KEY,
+            "POST",
+            "/secrets/db",
+            DIGEST,
+            "1700000000",
+            "00ff",
+            ""
+        ));
+    }
+
+    #[test]
+    fn freshness_allows_clock_skew() {
+        let now = unix_timestamp();
+        assert!(is_fresh(&now.to_string()));
+        assert!(is_fresh(&(now - MAX_CLOCK_SKEW_SECONDS).to_string()));
+        assert!(!is_fresh(&(now - MAX_CLOCK_SKEW_SECONDS - 10).to_string()));
+        assert!(!is_fresh(&(now + MAX_CLOCK_SKEW_SECONDS + 10).to_string()));
+        assert!(!is_fresh("yesterday"));
+    }
+
+    #[test]
+    fn nonce_is_accepted_once_on_its_connection() {
+        let nonces = NonceStore::default();
+        let nonce = nonces.issue(ConnectionId(1)).unwrap();
+        assert!(!nonces.consume(ConnectionId(2), &nonce));
+        assert!(nonces.consume(ConnectionId(1), &nonce));
+        assert!(!nonces.consume(ConnectionId(1), &nonce));
+        assert!(!nonces.consume(ConnectionId(1), "00ff"));
+    }
+
+    #[test]
+    fn reissuing_replaces_the_nonce_of_the_connection() {
+        let nonces = NonceStore::default();
+        let first = nonces.issue(ConnectionId(1)).unwrap();
+        let second = nonces.issue(ConnectionId(1)).unwrap();
+        assert!(!nonces.consume(ConnectionId(1), &first));
+        assert!(nonces.consume(ConnectionId(1), &second));
+    }
+
+    #[test]
+    fn flooding_evicts_the_oldest_nonces() {
+        let nonces = NonceStore::default();
+        let old = nonces.issue(ConnectionId(0)).unwrap();
+        for connection in 1..=MAX_OUTSTANDING_NONCES as u64 {
+            nonces.issue(ConnectionId(connection)).unwrap();
+        }
+        let new = nonces.issue(ConnectionId(u64::MAX)).unwrap();
+
+        assert!(nonces.issued.lock().unwrap().len() <= MAX_OUTSTANDING_NONCES);
+        assert!(!nonces.consume(ConnectionId(0), &old));
+        assert!(nonces.consume(ConnectionId(u64::MAX), &new));
+    }
+}
@@ -0,0 +1,158 @@
+// Where the receiver delivers the content of a secret to.
+//
+// eg;
+// sink = { type = "file" }
+// sink = { type = "keyring", keyring = "persistent", keyring_name = "postgres", description = "db:password" }
+// sink = { type = "credential", encrypted = true }
+// sink = { type = "env_file", path = "/run/postgres/secrets.env", key = "PGPASSWORD" }
+// sink = { type = "command", argv = ["wg", "set", "wg0", "private-key", "/dev/stdin"] }
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sink {
+    // Atomically replaces the file at `destination_path`.
+    #[default]
+    File,
+    // Stores the content as a "user" key, never touching the filesystem.
+    Keyring(KeyringSink),
+    // Stores the content as a systemd credential, for use with `LoadCredential=`.
+    Credential(CredentialSink),
+    // Merges the content as a `KEY=value` line into a generated file, for use with
+    // `EnvironmentFile=`.
+    EnvFile(EnvFileSink),
+    // Runs a command as the secret's owner and group with the content on its standard input.
+    Command(CommandSink),
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct KeyringSink {
+    pub keyring: Keyring,
+    // Name of a keyring linked into the chosen keyring, created when missing.
+    #[serde(default)]
+    pub keyring_name: Option<String>,
+    // Defaults to the name of the secret.
+    #[serde(default)]
+    pub description: Option<String>,
+    // Hexadecimal permission mask, see keyctl_setperm(3).
+    #[serde(default)]
+    pub permissions: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Keyring {
+    // The user keyring of the secret's owner.
+    User,
+    // The session keyring the receiver process inherited, not a keyring of the secret's owner.
+    Session,
+    // The persistent keyring of the secret's owner, survives the receiver process.
+    Persistent,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialSink {
+    // Defaults to the name of the secret.
+    #[serde(default)]
+    pub name: Option<String>,
+    // Encrypts the credential with `systemd-creds encrypt`, for use with
+    // `LoadCredentialEncrypted=`.
+    #[serde(default)]
+    pub encrypted: bool,
+    // Value for the `--with-key=` option of `systemd-creds encrypt`.
+    #[serde(default)]
+    pub with_key: Option<String>,
+    // Defaults to "/run/credstore", or "/run/credstore.encrypted" for encrypted credentials.
+    #[serde(default)]
+    pub directory: Option<std::path::PathBuf>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EnvFileSink {
+    // Secrets sharing this path end up in the same file.
+    pub path: std::path::PathBuf,
+    // Defaults to the name of the secret in upper case, with other characters than letters and
+    // digits replaced by underscores.
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+impl EnvFileSink {
+    pub fn key(&self, secret_name: &str) -> String {
+        match &self.key {
+            Some(key) => key.clone(),
+            None => secret_name
+                .chars()
+                .map(|c| match c.is_ascii_alphanumeric() {
+                    true => c.to_ascii_uppercase(),
+                    false => '_',
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSink {
+    pub argv: Vec<String>,
+    // The command is killed when it runs longer, failing the upload. (Default 30)
+    #[serde(default = "super::command::default_timeout")]
+    pub timeout_seconds: u64,
+    // Files and directories the command writes to. The receiver confines writes of itself and of
+    // everything it starts to the destinations of the manifest, writes elsewhere fail.
+    #[serde(default)]
+    pub writable_paths: Vec<std::path::PathBuf>,
+}
+
+impl Sink {
+    // Only file sinks write to the destination path.
+    pub fn uses_destination_path(&self) -> bool {
+        matches!(self, Sink::File)
+    }
+
+    // Where the content of the secret ends up, for the audit log. eg; "/etc/db.conf",
+    // "keyring:persistent/db:password", "credential:/run/credstore/db",
+    // "env_file:/run/postgres/secrets.env#PGPASSWORD" or "command:wg".
+    pub fn describe_destination(
+        &self,
+        secret_name: &str,
+        destination_path: Option<&std::path::Path>,
+    ) -> String {
+        match self {
+            Sink::File => destination_path
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            Sink::Keyring(keyring) => {
+                let name = match keyring.keyring {
+                    Keyring::User => "user",
+                    Keyring::Session => "session",
+                    Keyring::Persistent => "persistent",
+                };
+                let mut destination = format!("keyring:{}/", name);
+                if let Some(keyring_name) = &keyring.keyring_name {
+                    destination.push_str(keyring_name);
+                    destination.push('/');
+                }
+                destination.push_str(keyring.description.as_deref().unwrap_or(secret_name));
+                destination
+            }
+            Sink::Credential(credential) => format!(
+                "credential:{}",
+                super::credential::directory(credential)
+                    .join(credential.name.as_deref().unwrap_or(secret_name))
+                    .display()
+            ),
+            Sink::EnvFile(env_file) => format!(
+                "env_file:{}#{}",
+                env_file.path.display(),
+                env_file.key(secret_name)
+            ),
+            Sink::Command(command) => format!(
+                "command:{}",
+                command.argv.first().map_or("", String::as_str)
+            ),
+        }
+    }
+}
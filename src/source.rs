@@ -0,0 +1,383 @@
+use super::memory::SecretBuffer;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use std::path::{Path, PathBuf};
+
+// Where the sender retrieves the content of a secret from.
+//
+// eg;
+// source = { type = "file", path = "/run/secrets/db-password" }
+// source = { type = "env", variable = "DB_PASSWORD" }
+// source = { type = "literal", value = "log_level=debug" }
+// source = { type = "stdin" }
+// source = { type = "command", argv = ["vault", "kv", "get", "-field=password", "secret/db"] }
+// source = { type = "generate", kind = "password", length = 24 }
+// source = { type = "directory", path = "/etc/ssl/bundle" }
+// source = { type = "glob", pattern = "/etc/ssl/bundle/**/*.pem" }
+// source = { type = "age", path = "secrets/db-password.age", identity_path = "/root/.config/bss/age.key" }
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    File {
+        path: PathBuf,
+    },
+    Env {
+        variable: String,
+    },
+    // WARN; The value is stored in the manifest itself, only use this for non-sensitive data!
+    Literal {
+        value: String,
+    },
+    // NOTE; Standard input can only be consumed once, so at most one secret can use it.
+    Stdin,
+    // The standard output of the command is the secret. A non-zero exit status fails the transfer.
+    Command {
+        argv: Vec<String>,
+        // The command is killed when it runs longer, failing the transfer. (Default 30)
+        #[serde(default = "super::command::default_timeout")]
+        timeout_seconds: u64,
+    },
+    // Random material created at seed time, see [super::generate::Generator].
+    Generate {
+        #[serde(flatten)]
+        generator: super::generate::Generator,
+        // Keeps the generated value on the host so the guest receives the same secret next time.
+        #[serde(default)]
+        persist_path: Option<PathBuf>,
+    },
+    // Every regular file below the directory, see [Source::expand].
+    Directory {
+        path: PathBuf,
+    },
+    // Every regular file matching the pattern, see [Source::expand].
+    Glob {
+        pattern: String,
+    },
+    // An age encrypted file, binary or armored, decrypted in memory with the X25519 identities of
+    // the identity file.
+    Age {
+        path: PathBuf,
+        identity_path: PathBuf,
+    },
+}
+
+// The content of a secret, ready to be transferred.
+pub enum Payload {
+    File { file: tokio::fs::File, length: u64 },
+    Memory(SecretBuffer),
+}
+
+impl Payload {
+    pub fn len(&self) -> u64 {
+        match self {
+            Payload::File { length, .. } => *length,
+            Payload::Memory(data) => data.len() as u64,
+        }
+    }
+
+    pub fn as_memory(&self) -> Option<&[u8]> {
+        match self {
+            Payload::File { .. } => None,
+            Payload::Memory(data) => Some(data.as_slice()),
+        }
+    }
+
+    pub async fn into_memory(self) -> std::io::Result<SecretBuffer> {
+        match self {
+            Payload::File { mut file, length } => {
+                use tokio::io::AsyncReadExt;
+
+                let length = usize::try_from(length).map_err(std::io::Error::other)?;
+                let mut data = SecretBuffer::with_capacity(length)?;
+                data.read_exact_from(&mut file, length).await?;
+                // One byte tells a file that grew since it was opened apart, without reallocating
+                if file.read(&mut [0u8; 1]).await? != 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "file changed while reading",
+                    ));
+                }
+                Ok(data)
+            }
+            Payload::Memory(data) => Ok(data),
+        }
+    }
+
+    // Reads the content for the audit log. Files are read once for the digest and rewound.
+    //
+    // NOTE; A file changing in between is sent with content the digest doesn't cover
+    pub async fn content(&mut self) -> std::io::Result<super::audit::Content> {
+        use tokio::io::AsyncSeekExt;
+
+        let mut content = super::audit::Content::default();
+        match self {
+            Payload::File { file, .. } => {
+                let mut chunk = SecretBuffer::new();
+                loop {
+                    chunk.clear();
+                    if chunk.read_chunk(file).await? == 0 {
+                        break;
+                    }
+                    content.update(&chunk);
+                }
+                file.rewind().await?;
+            }
+            Payload::Memory(data) => content.update(data),
+        }
+        Ok(content)
+    }
+
+    pub fn into_body(self) -> BoxBody<Bytes, std::io::Error> {
+        use futures_util::TryStreamExt;
+        use http_body_util::{BodyExt, Full, StreamBody};
+        use hyper::body::Frame;
+
+        match self {
+            Payload::File { file, .. } => {
+                // Every chunk is read into locked memory, which is wiped once it's sent
+                let chunks = futures_util::stream::try_unfold(file, |mut file| async move {
+                    let mut chunk = SecretBuffer::new();
+                    match chunk.read_chunk(&mut file).await? {
+                        0 => Ok(None),
+                        _ => Ok(Some((chunk, file))),
+                    }
+                });
+                StreamBody::new(chunks.map_ok(|chunk| Frame::data(chunk.into_bytes()))).boxed()
+            }
+            Payload::Memory(data) => Full::new(data.into_bytes())
+                .map_err(|never| match never {})
+                .boxed(),
+        }
+    }
+}
+
+impl Source {
+    // Interprets the `source_path` shorthand. Paths containing glob characters become a glob,
+    // paths ending with a slash become a directory and anything else is a single file.
+    pub fn from_path(path: PathBuf) -> Self {
+        use std::os::unix::ffi::OsStrExt;
+
+        let bytes = path.as_os_str().as_bytes();
+        if bytes.iter().any(|byte| matches!(byte, b'*' | b'?' | b'[')) {
+            Source::Glob {
+                pattern: path.to_string_lossy().into_owned(),
+            }
+        } else if bytes.ends_with(b"/") {
+            Source::Directory { path }
+        } else {
+            Source::File { path }
+        }
+    }
+
+    // The `type` of the source in the manifest.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Source::File { .. } => "file",
+            Source::Env { .. } => "env",
+            Source::Literal { .. } => "literal",
+            Source::Stdin => "stdin",
+            Source::Command { .. } => "command",
+            Source::Generate { .. } => "generate",
+            Source::Directory { .. } => "directory",
+            Source::Glob { .. } => "glob",
+            Source::Age { .. } => "age",
+        }
+    }
+
+    // Sources that expand into many files must be delivered into a destination directory.
+    pub fn is_tree(&self) -> bool {
+        matches!(self, Source::Directory { .. } | Source::Glob { .. })
+    }
+
+    // Lists the files matched by a directory or glob source, as pairs of the '/' separated path
+    // relative to the source root and the full path. The root of a glob is the longest leading
+    // path without glob characters.
+    pub fn expand(&self) -> Result<Vec<(String, PathBuf)>, Box<dyn std::error::Error>> {
+        let (root, mut files) = match self {
+            Source::Directory { path } => {
+                let mut files = Vec::new();
+                walk_directory(path, &mut files)
+                    .map_err(|e| format!("failed to walk '{}': {}", path.display(), e))?;
+                (path.clone(), files)
+            }
+            Source::Glob { pattern } => {
+                let root: PathBuf = Path::new(pattern)
+                    .components()
+                    .take_while(|component| {
+                        !component
+                            .as_os_str()
+                            .to_string_lossy()
+                            .contains(['*', '?', '['])
+                    })
+                    .collect();
+                let mut files = Vec::new();
+                for path in glob::glob(pattern)? {
+                    let path = path?;
+                    if path.is_file() {
+                        files.push(path);
+                    }
+                }
+                (root, files)
+            }
+            _ => return Err("only directory and glob sources expand into files".into()),
+        };
+
+        files.sort();
+        let mut expanded = Vec::with_capacity(files.len());
+        for path in files {
+            let relative = path.strip_prefix(&root)?;
+            let relative = relative
+                .components()
+                .map(|component| component.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("path '{}' is not valid UTF-8", path.display()))?
+                .join("/");
+            expanded.push((relative, path));
+        }
+
+        if expanded.is_empty() {
+            return Err(format!("{:?} matched no files", self).into());
+        }
+
+        Ok(expanded)
+    }
+
+    pub async fn open(&self) -> Result<Payload, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            Source::File { path } => {
+                let file = tokio::fs::File::open(path)
+                    .await
+                    .map_err(|e| format!("failed to open '{}': {}", path.display(), e))?;
+                let length = file.metadata().await?.len();
+                Ok(Payload::File { file, length })
+            }
+            Source::Env { variable } => {
+                use std::os::unix::ffi::OsStringExt;
+
+                let value = std::env::var_os(variable)
+                    .ok_or_else(|| format!("environment variable '{}' is not set", variable))?;
+                Ok(Payload::Memory(value.into_vec().try_into()?))
+            }
+            Source::Literal { value } => Ok(Payload::Memory(value.as_bytes().try_into()?)),
+            Source::Stdin => {
+                let mut data = SecretBuffer::new();
+                data.read_from(&mut tokio::io::stdin()).await?;
+                Ok(Payload::Memory(data))
+            }
+            Source::Command {
+                argv,
+                timeout_seconds,
+            } => {
+                let (program, arguments) = argv
+                    .split_first()
+                    .ok_or("command source requires a non-empty argv")?;
+                let mut child = tokio::process::Command::new(program)
+                    .args(arguments)
+                    .stdin(std::process::Stdio::null())
+                    .stdout(std::process::Stdio::piped())
+                    .stderr(std::process::Stdio::inherit())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|e| format!("failed to run '{}': {}", program, e))?;
+                let run = async {
+                    let mut data = SecretBuffer::new();
+                    if let Some(mut stdout) = child.stdout.take() {
+                        data.read_from(&mut stdout).await?;
+                    }
+                    let status = child.wait().await?;
+                    Ok::<_, std::io::Error>((data, status))
+                };
+                // NOTE; The command is killed when the child is dropped
+                let timeout = std::time::Duration::from_secs(*timeout_seconds);
+                let Ok(result) = tokio::time::timeout(timeout, run).await else {
+                    return Err(format!(
+                        "command '{}' timed out after {} seconds",
+                        program, timeout_seconds
+                    ))?;
+                };
+                let (data, status) = result?;
+                if !status.success() {
+                    return Err(format!("command '{}' failed with {}", program, status))?;
+                }
+                Ok(Payload::Memory(data))
+            }
+            Source::Generate {
+                generator,
+                persist_path,
+            } => {
+                let secret =
+                    super::generate::load_or_generate(generator, persist_path.as_deref()).await?;
+                Ok(Payload::Memory(secret.try_into()?))
+            }
+            Source::Age {
+                path,
+                identity_path,
+            } => {
+                let identities =
+                    super::encryption::read_identities(identity_path).map_err(|e| e.to_string())?;
+                let ciphertext = tokio::fs::read(path)
+                    .await
+                    .map_err(|e| format!("failed to read '{}': {}", path.display(), e))?;
+                let plaintext = super::encryption::decrypt_in_memory(&identities, &ciphertext)
+                    .map_err(|e| format!("failed to decrypt '{}': {}", path.display(), e))?;
+                Ok(Payload::Memory(plaintext))
+            }
+            Source::Directory { .. } | Source::Glob { .. } => {
+                Err("directory and glob sources must be expanded before opening")?
+            }
+        }
+    }
+}
+
+// Lists the files below the directory, recursively.
+//
+// NOTE; Follows symlinks, every directory is walked once so symlink loops end
+pub fn walk_directory(directory: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut visited = std::collections::HashSet::new();
+    walk_directory_once(directory, files, &mut visited)
+}
+
+fn walk_directory_once(
+    directory: &Path,
+    files: &mut Vec<PathBuf>,
+    visited: &mut std::collections::HashSet<(u64, u64)>,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(directory)?;
+    if !visited.insert((metadata.dev(), metadata.ino())) {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let metadata = std::fs::metadata(&path)?;
+        if metadata.is_dir() {
+            walk_directory_once(&path, files, visited)?;
+        } else if metadata.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_directory_ends_symlink_loops() {
+        let root = std::env::temp_dir().join(format!("bss-walk-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a/file"), b"content").unwrap();
+        std::os::unix::fs::symlink("..", root.join("a/loop")).unwrap();
+
+        let mut files = Vec::new();
+        let result = walk_directory(&root, &mut files);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        result.unwrap();
+        assert_eq!(files, vec![root.join("a/file")]);
+    }
+}
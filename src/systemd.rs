@@ -0,0 +1,167 @@
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+
+// The first descriptor passed by the service manager, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+// The name of the passed socket the receiver adopts when several are passed, set with
+// `FileDescriptorName=` in the socket unit.
+pub const RECEIVE_SOCKET_NAME: &str = "receive";
+
+// A listening socket passed by the service manager on socket activation.
+pub struct ActivatedSocket {
+    pub fd: OwnedFd,
+    // `FileDescriptorName=` of the socket unit, defaults to the name of the unit.
+    pub name: String,
+}
+
+// Takes over the sockets passed by the service manager, none when not socket activated. The
+// environment variables are removed so started commands don't take them over again.
+pub fn listen_fds() -> Result<Vec<ActivatedSocket>, Box<dyn std::error::Error>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+    for variable in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        // NOTE; Called during setup, before any other thread is started
+        std::env::remove_var(variable);
+    }
+
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return Ok(Vec::new());
+    };
+    // The variables are inherited by children of the activated process, who must ignore them
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = count
+        .parse()
+        .map_err(|e| format!("invalid LISTEN_FDS '{}': {}", count, e))?;
+    let names: Vec<&str> = names
+        .as_deref()
+        .map_or(Vec::new(), |names| names.split(':').collect());
+
+    let mut sockets = Vec::new();
+    for (index, fd) in (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)).enumerate() {
+        // Passed descriptors survive exec, the commands of sinks must not inherit them
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(format!(
+                "invalid socket descriptor {} passed by the service manager: {}",
+                fd,
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+        sockets.push(ActivatedSocket {
+            // SAFETY; The service manager passes the descriptors to this process only
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            name: names.get(index).unwrap_or(&"unknown").to_string(),
+        });
+    }
+
+    Ok(sockets)
+}
+
+// Picks the socket to receive on, either the only one passed or the one named
+// [RECEIVE_SOCKET_NAME].
+pub fn receive_socket(
+    mut sockets: Vec<ActivatedSocket>,
+) -> Result<Option<ActivatedSocket>, Box<dyn std::error::Error>> {
+    if sockets.len() <= 1 {
+        return Ok(sockets.pop());
+    }
+
+    match sockets
+        .iter()
+        .position(|socket| socket.name == RECEIVE_SOCKET_NAME)
+    {
+        Some(index) => Ok(Some(sockets.swap_remove(index))),
+        None => Err(format!(
+            "the service manager passed {} sockets, name the one to receive on '{}' with FileDescriptorName=",
+            sockets.len(),
+            RECEIVE_SOCKET_NAME
+        )
+        .into()),
+    }
+}
+
+// Reports the state of the receiver to the service manager, see sd_notify(3).
+pub struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    address: std::os::unix::net::SocketAddr,
+    // The receiver stops when the session isn't completed before, the service manager is asked to
+    // wait until then.
+    deadline: std::time::Instant,
+}
+
+impl Notifier {
+    // Takes over the notification socket of the service manager, none when not started by it. The
+    // environment variable is removed so started commands don't report in place of the receiver.
+    pub fn from_environment(deadline: std::time::Instant) -> Option<Self> {
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        // NOTE; Called during setup, before any other thread is started
+        std::env::remove_var("NOTIFY_SOCKET");
+
+        let notifier = || -> std::io::Result<Self> {
+            use std::os::unix::ffi::OsStrExt;
+
+            let address = match path.as_bytes().strip_prefix(b"@") {
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    std::os::unix::net::SocketAddr::from_abstract_name(name)?
+                }
+                None => std::os::unix::net::SocketAddr::from_pathname(&path)?,
+            };
+            let socket = std::os::unix::net::UnixDatagram::unbound()?;
+            Ok(Notifier {
+                socket,
+                address,
+                deadline,
+            })
+        };
+        match notifier() {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                log::warn!(
+                    path:? = path, error:% = e;
+                    "Failed to use the notification socket of the service manager"
+                );
+                None
+            }
+        }
+    }
+
+    // Failing to notify is reported but not fatal, the service manager only loses track.
+    fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            log::warn!(error:% = e; "Failed to notify the service manager");
+        }
+    }
+
+    // Signals that the receiver accepts connections.
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!(
+            "READY=1\nSTATUS={}\nEXTEND_TIMEOUT_USEC={}",
+            status,
+            self.remaining_usec()
+        ));
+    }
+
+    // Updates the status line of the unit while the session is running.
+    pub fn progress(&self, status: &str) {
+        self.notify(&format!(
+            "STATUS={}\nEXTEND_TIMEOUT_USEC={}",
+            status,
+            self.remaining_usec()
+        ));
+    }
+
+    // Updates the status line of the unit after the session completed, the deadline is over.
+    pub fn completed(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    fn remaining_usec(&self) -> u128 {
+        self.deadline
+            .saturating_duration_since(std::time::Instant::now())
+            .as_micros()
+    }
+}
@@ -0,0 +1,363 @@
+use rustls::client::danger::{ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// The amount of seconds a peer gets to complete the handshake.
+const HANDSHAKE_TIMEOUT_SECONDS: u64 = 10;
+
+// The amount of handshakes the receiver performs at the same time.
+const MAX_CONCURRENT_HANDSHAKES: usize = 16;
+
+// The name the sender expects in the certificate of the receiver, unless provided.
+pub const DEFAULT_SERVER_NAME: &str = "bss";
+
+// Mutual TLS on top of the transport. Both sides present a certificate and verify the certificate
+// of their peer, by SPKI pin or by the issuing CA.
+#[derive(Default)]
+pub struct TlsSettings {
+    pub certificate: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // CA certificates issuing the certificate of the peer.
+    pub ca: Option<PathBuf>,
+    // SHA-256 digests of the SubjectPublicKeyInfo of acceptable peer certificates.
+    pub pins: Vec<[u8; 32]>,
+}
+
+impl TlsSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.certificate.is_some()
+            || self.key.is_some()
+            || self.ca.is_some()
+            || !self.pins.is_empty()
+    }
+
+    // Loads the certificate, key and trust anchors. Returns nothing when TLS is not requested.
+    fn load(&self) -> Result<Option<Credentials>, Box<dyn std::error::Error>> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        let (Some(certificate), Some(key)) = (&self.certificate, &self.key) else {
+            return Err("TLS requires both --tls-cert and --tls-key".into());
+        };
+        if self.ca.is_none() && self.pins.is_empty() {
+            return Err("TLS requires --tls-ca or --tls-pin to authenticate the peer".into());
+        }
+
+        let roots = match &self.ca {
+            None => None,
+            Some(path) => {
+                let mut roots = rustls::RootCertStore::empty();
+                for certificate in load_certificates(path)? {
+                    roots.add(certificate).map_err(|e| {
+                        format!("invalid CA certificate in '{}': {}", path.display(), e)
+                    })?;
+                }
+                Some(Arc::new(roots))
+            }
+        };
+
+        Ok(Some(Credentials {
+            chain: load_certificates(certificate)?,
+            key: load_private_key(key)?,
+            roots,
+        }))
+    }
+}
+
+struct Credentials {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    roots: Option<Arc<rustls::RootCertStore>>,
+}
+
+// Parses the argument of --tls-pin, the hex encoded SHA-256 digest of a SubjectPublicKeyInfo.
+//
+// eg; openssl x509 -in peer.pem -pubkey -noout | openssl pkey -pubin -outform der | sha256sum
+pub fn parse_pin(value: &str) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let mut pin = [0u8; 32];
+    hex::decode_to_slice(value, &mut pin)
+        .map_err(|e| format!("invalid SPKI pin '{}': {}", value, e))?;
+    Ok(pin)
+}
+
+fn load_certificates(
+    path: &Path,
+) -> Result<Vec<CertificateDer<'static>>, Box<dyn std::error::Error>> {
+    let contents = std::fs::read(path)
+        .map_err(|e| format!("failed to read certificates '{}': {}", path.display(), e))?;
+    let certificates = rustls_pemfile::certs(&mut contents.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("failed to parse certificates '{}': {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificates found in '{}'", path.display()).into());
+    }
+
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, Box<dyn std::error::Error>> {
+    let contents = std::fs::read(path)
+        .map_err(|e| format!("failed to read private key '{}': {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut contents.as_slice())
+        .map_err(|e| format!("failed to parse private key '{}': {}", path.display(), e))?
+        .ok_or_else(|| format!("no private key found in '{}'", path.display()).into())
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+// Builds the receive side of the handshake, which demands a client certificate.
+pub fn acceptor(
+    settings: &TlsSettings,
+) -> Result<Option<tokio_rustls::TlsAcceptor>, Box<dyn std::error::Error>> {
+    let Some(credentials) = settings.load()? else {
+        return Ok(None);
+    };
+
+    let provider = provider();
+    let ca = match credentials.roots {
+        Some(roots) => Some(
+            rustls::server::WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+                .build()?,
+        ),
+        None => None,
+    };
+    let verifier = PeerVerifier {
+        pins: settings.pins.clone(),
+        ca,
+        algorithms: provider.signature_verification_algorithms,
+    };
+
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(Arc::new(verifier))
+        .with_single_cert(credentials.chain, credentials.key)?;
+    Ok(Some(Arc::new(config).into()))
+}
+
+// The send side of the handshake, which presents a client certificate.
+pub struct Connector {
+    connector: tokio_rustls::TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl Connector {
+    pub fn new(
+        settings: &TlsSettings,
+        server_name: &str,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Some(credentials) = settings.load()? else {
+            return Ok(None);
+        };
+
+        let provider = provider();
+        let ca = match credentials.roots {
+            Some(roots) => Some(
+                rustls::client::WebPkiServerVerifier::builder_with_provider(
+                    roots,
+                    provider.clone(),
+                )
+                .build()?,
+            ),
+            None => None,
+        };
+        let verifier = PeerVerifier {
+            pins: settings.pins.clone(),
+            ca,
+            algorithms: provider.signature_verification_algorithms,
+        };
+
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(credentials.chain, credentials.key)?;
+        Ok(Some(Connector {
+            connector: Arc::new(config).into(),
+            server_name: ServerName::try_from(server_name.to_string())
+                .map_err(|e| format!("invalid TLS server name '{}': {}", server_name, e))?,
+        }))
+    }
+
+    pub async fn connect(
+        &self,
+        connection: super::transport::Connection,
+    ) -> std::io::Result<super::transport::Connection> {
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), connection)
+            .await?;
+        Ok(Box::new(stream))
+    }
+}
+
+// Performs the handshake on every accepted connection. Connections failing the handshake are
+// dropped, so a misbehaving peer can't stop the server.
+pub fn accept(
+    incoming: super::transport::Incoming,
+    acceptor: tokio_rustls::TlsAcceptor,
+    metrics: &'static super::metrics::Metrics,
+) -> super::transport::Incoming {
+    use futures_util::StreamExt;
+
+    let handshakes = incoming.map(move |connection| {
+        let acceptor = acceptor.clone();
+        async move {
+            let super::transport::Accepted { peer, connection } = match connection {
+                Ok(c) => c,
+                Err(e) => return Some(Err(e)),
+            };
+            let handshake = tokio::time::timeout(
+                std::time::Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS),
+                acceptor.accept(connection),
+            );
+            match handshake.await {
+                Ok(Ok(stream)) => Some(Ok(super::transport::Accepted {
+                    peer,
+                    connection: Box::new(stream),
+                })),
+                Ok(Err(e)) => {
+                    log::warn!(peer:% = peer, error:% = e; "TLS handshake failed");
+                    metrics.record_rejected_peer();
+                    None
+                }
+                Err(_) => {
+                    log::warn!(peer:% = peer; "TLS handshake timed out");
+                    metrics.record_rejected_peer();
+                    None
+                }
+            }
+        }
+    });
+
+    Box::pin(
+        handshakes
+            .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+            .filter_map(std::future::ready),
+    )
+}
+
+// Accepts the certificate of the peer when its public key is pinned or it's issued by the CA.
+//
+// NOTE; Pinned certificates are trusted for their key alone, their validity period is not checked.
+#[derive(Debug)]
+struct PeerVerifier<V: ?Sized> {
+    pins: Vec<[u8; 32]>,
+    ca: Option<Arc<V>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl<V: ?Sized> PeerVerifier<V> {
+    fn is_pinned(&self, end_entity: &CertificateDer<'_>) -> Result<bool, rustls::Error> {
+        use sha2::Digest;
+
+        if self.pins.is_empty() {
+            return Ok(false);
+        }
+
+        let (_, certificate) = x509_parser::parse_x509_certificate(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let digest: [u8; 32] =
+            sha2::Sha256::digest(certificate.tbs_certificate.subject_pki.raw).into();
+        Ok(self.pins.contains(&digest))
+    }
+
+    fn unknown_peer() -> rustls::Error {
+        rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+    }
+}
+
+impl ClientCertVerifier for PeerVerifier<dyn ClientCertVerifier> {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        match &self.ca {
+            Some(ca) => ca.root_hint_subjects(),
+            None => &[],
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if self.is_pinned(end_entity)? {
+            return Ok(ClientCertVerified::assertion());
+        }
+        match &self.ca {
+            Some(ca) => ca.verify_client_cert(end_entity, intermediates, now),
+            None => Err(Self::unknown_peer()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl ServerCertVerifier for PeerVerifier<rustls::client::WebPkiServerVerifier> {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.is_pinned(end_entity)? {
+            return Ok(ServerCertVerified::assertion());
+        }
+        match &self.ca {
+            Some(ca) => {
+                ca.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            }
+            None => Err(Self::unknown_peer()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,51 @@
+// Pre-shared bearer token, required on every request when configured.
+
+use super::memory::SecretBuffer;
+use zeroize::Zeroizing;
+
+// Reads the token from a file, surrounding whitespace is ignored.
+pub fn read_token_file(path: &std::path::Path) -> Result<SecretBuffer, String> {
+    let content = std::fs::read(path)
+        .map(Zeroizing::new)
+        .map_err(|e| format!("failed to read token file '{}': {}", path.display(), e))?;
+    validate(content.trim_ascii())
+}
+
+// Reads the token from the kernel command line argument "<key>=<token>".
+pub fn read_token_cmdline(key: &str) -> Result<SecretBuffer, String> {
+    let cmdline = std::fs::read("/proc/cmdline")
+        .map(Zeroizing::new)
+        .map_err(|e| format!("failed to read kernel command line: {}", e))?;
+    let token = cmdline
+        .split(|byte| byte.is_ascii_whitespace())
+        .find_map(|argument| {
+            argument
+                .strip_prefix(key.as_bytes())
+                .and_then(|rest| rest.strip_prefix(b"="))
+        })
+        .ok_or_else(|| format!("kernel command line has no '{}' argument", key))?;
+    validate(token)
+}
+
+// The token is transferred inside an HTTP header.
+fn validate(token: &[u8]) -> Result<SecretBuffer, String> {
+    if token.is_empty() {
+        return Err("token is empty".into());
+    }
+    if !token.iter().all(|byte| byte.is_ascii_graphic()) {
+        return Err("token must consist of printable ASCII characters without spaces".into());
+    }
+
+    SecretBuffer::try_from(token).map_err(|e| format!("failed to store token: {}", e))
+}
+
+// Compares the token from the authorization header in constant time.
+pub fn verify(expected: &[u8], authorization: Option<&str>) -> bool {
+    use subtle::ConstantTimeEq;
+
+    let provided = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        Some(provided) => provided.as_bytes(),
+        None => return false,
+    };
+    expected.ct_eq(provided).into()
+}
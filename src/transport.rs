@@ -0,0 +1,333 @@
+use std::path::PathBuf;
+use std::pin::Pin;
+
+// The default rendezvous point when no address is provided.
+const DEFAULT_UNIX_SOCKET: &str = "/tmp/warp.sock";
+
+// Any bidirectional byte stream a HTTP connection can run over.
+pub trait Io: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static {}
+impl<T> Io for T where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static {}
+
+pub type Connection = Box<dyn Io>;
+
+// Accepted connections, ready to be served.
+pub type Incoming = Pin<Box<dyn futures::Stream<Item = std::io::Result<Accepted>> + Send>>;
+
+// The other end of an accepted connection, for logging.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Vsock { cid: u32, port: u32 },
+    // Credentials of the connecting process, see unix(7) SO_PEERCRED.
+    Unix { pid: i32, uid: u32 },
+    Ip(std::net::SocketAddr),
+    Unknown,
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Vsock { cid, port } => write!(f, "vsock:{}:{}", cid, port),
+            Peer::Unix { pid, uid } => write!(f, "unix:pid={},uid={}", pid, uid),
+            Peer::Ip(address) => write!(f, "tcp:{}", address),
+            Peer::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// Identifies the connection a request arrived on, unique during a run of the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub u64);
+
+// An accepted connection and who's on the other end.
+pub struct Accepted {
+    pub peer: Peer,
+    pub connection: Connection,
+}
+
+impl tokio::io::AsyncRead for Accepted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.connection).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for Accepted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.connection).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.connection).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.connection).poll_shutdown(cx)
+    }
+}
+
+// Where the receiver listens and the sender connects to.
+#[derive(Debug, Clone)]
+pub enum Address {
+    Vsock { cid: u32, port: u32 },
+    Unix(PathBuf),
+    Ip(std::net::SocketAddr),
+}
+
+impl Address {
+    // Picks the first provided address in the order VSOCK > UNIX > IP, see [super::HELP].
+    pub fn from_settings(
+        settings: &super::GlobalSettings,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(cid) = settings.vsock_address {
+            return Ok(Address::Vsock {
+                cid,
+                port: settings.socket_port,
+            });
+        }
+        if let Some(path) = &settings.unix_socket {
+            return Ok(Address::Unix(path.clone()));
+        }
+        if let Some(ip) = settings.ip_address {
+            let port = u16::try_from(settings.socket_port)
+                .map_err(|_| format!("port {} is out of range for IP", settings.socket_port))?;
+            return Ok(Address::Ip(std::net::SocketAddr::new(ip, port)));
+        }
+
+        Ok(Address::Unix(DEFAULT_UNIX_SOCKET.into()))
+    }
+
+    // Binds the listening socket without a runtime, so it can be shared before forking.
+    pub fn bind(&self) -> std::io::Result<Listener> {
+        let listener = match self {
+            Address::Vsock { cid, port } => {
+                Listener::Vsock(vsock::VsockListener::bind_with_cid_port(*cid, *port)?)
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => Listener::Unix(super::unix_socket::DeleteOnDrop::bind(path)?),
+            #[cfg(not(unix))]
+            Address::Unix(_) => {
+                return Err(std::io::Error::other("Must run under Unix-like platform!"));
+            }
+            Address::Ip(address) => Listener::Ip(std::net::TcpListener::bind(address)?),
+        };
+
+        Ok(listener)
+    }
+
+    pub async fn connect(&self) -> std::io::Result<Connection> {
+        let connection: Connection = match self {
+            Address::Vsock { cid, port } => Box::new(
+                tokio_vsock::VsockStream::connect(tokio_vsock::VsockAddr::new(*cid, *port)).await?,
+            ),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                Box::new(super::unix_socket::connect_unix_sock_stream(path).await?)
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => {
+                return Err(std::io::Error::other("Must run under Unix-like platform!"));
+            }
+            Address::Ip(address) => Box::new(tokio::net::TcpStream::connect(address).await?),
+        };
+
+        Ok(connection)
+    }
+}
+
+// A bound socket, not yet accepting connections.
+pub enum Listener {
+    Vsock(vsock::VsockListener),
+    #[cfg(unix)]
+    Unix(super::unix_socket::DeleteOnDrop<std::os::unix::net::UnixListener>),
+    Ip(std::net::TcpListener),
+}
+
+impl Listener {
+    // Takes over a listening socket bound by another process, eg; the service manager on socket
+    // activation. The socket path of a UNIX socket is left to its creator.
+    pub fn adopt(fd: std::os::unix::io::OwnedFd) -> std::io::Result<Self> {
+        use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+
+        let option = |level, name| -> std::io::Result<libc::c_int> {
+            let mut value: libc::c_int = 0;
+            let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY; The value has the size passed as length
+            let result = unsafe {
+                libc::getsockopt(
+                    fd.as_raw_fd(),
+                    level,
+                    name,
+                    (&mut value as *mut libc::c_int).cast(),
+                    &mut length,
+                )
+            };
+            match result {
+                0 => Ok(value),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        };
+        if option(libc::SOL_SOCKET, libc::SO_TYPE)? != libc::SOCK_STREAM
+            || option(libc::SOL_SOCKET, libc::SO_ACCEPTCONN)? == 0
+        {
+            return Err(std::io::Error::other("not a listening stream socket"));
+        }
+
+        let listener = match option(libc::SOL_SOCKET, libc::SO_DOMAIN)? {
+            // SAFETY; The descriptor is an owned, listening socket of the matching family
+            libc::AF_VSOCK => {
+                Listener::Vsock(unsafe { vsock::VsockListener::from_raw_fd(fd.into_raw_fd()) })
+            }
+            #[cfg(unix)]
+            libc::AF_UNIX => Listener::Unix(super::unix_socket::DeleteOnDrop::adopt(fd.into())),
+            libc::AF_INET | libc::AF_INET6 => Listener::Ip(fd.into()),
+            domain => {
+                return Err(std::io::Error::other(format!(
+                    "unsupported socket family {}",
+                    domain
+                )))
+            }
+        };
+
+        Ok(listener)
+    }
+
+    // Leaves unlinking a UNIX socket path to another process, see
+    // [super::unix_socket::DeleteOnDrop::disarm].
+    pub fn disarm(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(listener) = self {
+            listener.disarm();
+        }
+    }
+
+    // The path of a UNIX socket this process unlinks when the listener is dropped.
+    pub fn unix_path(&self) -> Option<&std::path::Path> {
+        match self {
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.path(),
+            _ => None,
+        }
+    }
+
+    // Registers the socket with the runtime, must be called from within the runtime. Accepting
+    // continues after errors of a single connection or exhausted resources, see [is_transient].
+    pub fn incoming(self) -> std::io::Result<Incoming> {
+        use futures_util::{StreamExt, TryStreamExt};
+
+        let incoming: Incoming = match self {
+            Listener::Vsock(listener) => {
+                use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+                listener.set_nonblocking(true)?;
+                // SAFETY; The descriptor is a listening vsock socket owned by nobody else
+                let listener =
+                    unsafe { tokio_vsock::VsockListener::from_raw_fd(listener.into_raw_fd()) };
+                Box::pin(listener.incoming().map_ok(|stream| {
+                    let peer = match stream.peer_addr() {
+                        Ok(address) => Peer::Vsock {
+                            cid: address.cid(),
+                            port: address.port(),
+                        },
+                        Err(_) => Peer::Unknown,
+                    };
+                    Accepted {
+                        peer,
+                        connection: Box::new(stream),
+                    }
+                }))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => Box::pin(listener.into_stream()?.map_ok(|stream| {
+                let peer = match stream.peer_cred() {
+                    Ok(credentials) => Peer::Unix {
+                        pid: credentials.pid().unwrap_or_default(),
+                        uid: credentials.uid(),
+                    },
+                    Err(_) => Peer::Unknown,
+                };
+                Accepted {
+                    peer,
+                    connection: Box::new(stream),
+                }
+            })),
+            Listener::Ip(listener) => {
+                listener.set_nonblocking(true)?;
+                Box::pin(
+                    tokio_stream::wrappers::TcpListenerStream::new(
+                        tokio::net::TcpListener::from_std(listener)?,
+                    )
+                    .map_ok(|stream| Accepted {
+                        peer: stream.peer_addr().map_or(Peer::Unknown, Peer::Ip),
+                        connection: Box::new(stream),
+                    }),
+                )
+            }
+        };
+
+        Ok(Box::pin(incoming.filter_map(|accepted| async move {
+            match accepted {
+                Err(e) if is_transient(&e) => {
+                    log::warn!(error:% = e; "Failed accepting a connection, retrying");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    None
+                }
+                accepted => Some(accepted),
+            }
+        })))
+    }
+}
+
+// Pause after a failed accept, the listener fails again right away while descriptors or memory
+// are exhausted.
+const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+// Accept errors that concern a single connection or resources that are freed again, the listener
+// itself still works.
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::OutOfMemory
+    ) || matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EPROTO)
+    )
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Vsock { cid, port } => write!(f, "vsock:{}:{}", cid, port),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+            Address::Ip(address) => write!(f, "tcp:{}", address),
+        }
+    }
+}
+
+// Parses the argument of --vsock-address, "any" binds to every CID.
+pub fn parse_vsock_cid(value: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    match value {
+        "any" => Ok(tokio_vsock::VMADDR_CID_ANY),
+        value => Ok(value
+            .parse()
+            .map_err(|e| format!("invalid vsock CID '{}': {}", value, e))?),
+    }
+}
@@ -1,4 +1,3 @@
-use hyper_util::rt::TokioIo;
 use std::path::{Path, PathBuf};
 use tokio::net::UnixStream;
 use tokio_stream::wrappers::UnixListenerStream;
@@ -7,33 +6,72 @@ use tokio_stream::wrappers::UnixListenerStream;
 // the program, otherwise the next run will panic with E_ADDR_IN_USE.
 //
 // REF; https://stackoverflow.com/a/40218765
-pub struct DeleteOnDrop {
+pub struct DeleteOnDrop<L = UnixListenerStream> {
     // ERROR; Important to consider the lifetime of the owned object!
     // It's wrong to _only_ track the path without the listener object itself because
     // that leads to a footgun where the path is unlinked before the listener is shutdown!
-    path: PathBuf,
-    pub stream: UnixListenerStream,
+    path: Option<PathBuf>,
+    pub stream: L,
 }
 
-impl DeleteOnDrop {
+impl DeleteOnDrop<std::os::unix::net::UnixListener> {
+    // Binds without a runtime, see [DeleteOnDrop::into_stream].
     pub fn bind(path: impl AsRef<Path>) -> std::io::Result<Self> {
-        use tokio::net::UnixListener;
-
         let path = path.as_ref().to_owned();
-        UnixListener::bind(&path)
-            .map(|listener| UnixListenerStream::new(listener))
-            .map(|stream| DeleteOnDrop { path, stream })
+        std::os::unix::net::UnixListener::bind(&path).map(|stream| DeleteOnDrop {
+            path: Some(path),
+            stream,
+        })
+    }
+
+    // Takes over a listener bound by another process, the path is left in place on drop.
+    pub fn adopt(stream: std::os::unix::net::UnixListener) -> Self {
+        DeleteOnDrop { path: None, stream }
+    }
+
+    // Registers the listener with the runtime, the path is unlinked when the stream is dropped.
+    pub fn into_stream(mut self) -> std::io::Result<DeleteOnDrop> {
+        self.stream.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(self.stream.try_clone()?)?;
+        Ok(DeleteOnDrop {
+            path: self.path.take(),
+            stream: UnixListenerStream::new(listener),
+        })
     }
 }
 
-impl Drop for DeleteOnDrop {
+impl<L> DeleteOnDrop<L> {
+    // The path unlinked on drop, none once disarmed.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Leaves unlinking the path to another process holding the same listener, eg; when this
+    // process lacks the permission to unlink it.
+    pub fn disarm(&mut self) {
+        self.path = None;
+    }
+}
+
+impl<L> Drop for DeleteOnDrop<L> {
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.path).expect("Failed to remove the provided file path!");
+        let Some(path) = &self.path else {
+            return;
+        };
+        // NOTE; Never panic in drop, the path can be gone already, eg; unlinked by the other
+        // process holding the listener
+        match std::fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                log::warn!(path:? = path, error:% = e; "Failed to remove the socket path");
+            }
+        }
     }
 }
 
-impl std::ops::Deref for DeleteOnDrop {
-    type Target = UnixListenerStream;
+impl<L> std::ops::Deref for DeleteOnDrop<L> {
+    type Target = L;
 
     fn deref(&self) -> &Self::Target {
         &self.stream
@@ -42,15 +80,25 @@ impl std::ops::Deref for DeleteOnDrop {
 
 // WARN; Implementation required to make UnixListenerStream compatible with `Stream`
 // See [warp::server::Server::run_incoming]
-impl std::ops::DerefMut for DeleteOnDrop {
+impl<L> std::ops::DerefMut for DeleteOnDrop<L> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.stream
     }
 }
 
-pub async fn connect_unix_sock_stream(
-) -> Result<TokioIo<UnixStream>, Box<dyn std::error::Error + Send + Sync>> {
-    let stream = TokioIo::new(UnixStream::connect("/tmp/warp.sock").await.unwrap());
+// NOTE; Owning the listener through the stream keeps the path around until the server stops
+// polling for new connections.
+impl futures::Stream for DeleteOnDrop {
+    type Item = std::io::Result<UnixStream>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        std::pin::Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
 
-    Ok(stream)
+pub async fn connect_unix_sock_stream(path: impl AsRef<Path>) -> std::io::Result<UnixStream> {
+    UnixStream::connect(path).await
 }
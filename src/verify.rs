@@ -0,0 +1,350 @@
+// Compares the state of the guest against the manifest after seeding. Every secret reports whether
+// its destination exists, who owns it with which mode, and the digest of its content. Content is
+// compared against the digests of the plan printed by `send --dry-run`, when given.
+//
+// eg;
+// {"secrets":[{"secret":"db","destination":"/etc/db.conf","exists":true,"uid":0,"gid":0,"mode":"0400","digest":"9f86..","expected_digest":"9f86..","unverifiable":false,"drift":[]}]}
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(serde::Serialize, Debug, Default)]
+struct Status {
+    secret: String,
+    destination: String,
+    // Unset for keyring and command sinks, they leave nothing on the filesystem to inspect.
+    exists: Option<bool>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<String>,
+    // SHA-256 digest of the delivered content, unset when it can't be read back, eg; encrypted
+    // credentials.
+    digest: Option<String>,
+    expected_digest: Option<String>,
+    // Set when the plan has a digest but the content can't be read back, eg; keyring and command
+    // sinks, encrypted credentials or read failures. Not drift, but not verified either.
+    unverifiable: bool,
+    drift: Vec<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Report {
+    secrets: Vec<Status>,
+}
+
+// The part of the plan of the sender that's compared, see [super::plan::Transfer].
+#[derive(serde::Deserialize, Debug)]
+struct Plan {
+    secrets: Vec<PlannedSecret>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct PlannedSecret {
+    secret: String,
+    #[serde(default)]
+    digest: Option<String>,
+}
+
+pub fn verify_main(mut parser: lexopt::Parser) -> Result<(), Box<dyn std::error::Error>> {
+    use lexopt::prelude::*;
+
+    let mut manifest_path = None;
+    let mut identity = super::selector::Identity::detect_local();
+    let mut plan_path = None;
+
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("cid") => {
+                identity.cid = Some(parser.value()?.parse()?);
+            }
+            Long("hostname") => {
+                identity.hostname = Some(parser.value()?.string()?);
+            }
+            Long("role") => {
+                identity.roles.push(parser.value()?.string()?);
+            }
+            Long("plan") => {
+                plan_path = Some(std::path::PathBuf::from(parser.value()?));
+            }
+            Value(value) if manifest_path.is_none() => {
+                manifest_path = Some(value.into());
+            }
+            _ => return Err(arg.unexpected())?,
+        }
+    }
+
+    let Some(manifest_path) = manifest_path else {
+        println!("{}", super::HELP);
+        return Ok(());
+    };
+
+    let manifest = super::read_and_deserialize_manifest(manifest_path, &identity)?;
+    let plan = plan_path.map(|path| read_plan(&path)).transpose()?;
+
+    verify(manifest, plan.as_ref())
+}
+
+// Digests by transfer name, directory entries are named "<name>/<relative path>". Transfers the
+// sender couldn't digest have none.
+type Digests = BTreeMap<String, Option<String>>;
+
+fn read_plan(path: &Path) -> Result<Digests, Box<dyn std::error::Error>> {
+    let content = std::fs::read(path)
+        .map_err(|e| format!("failed to read plan '{}': {}", path.display(), e))?;
+    let plan: Plan = serde_json::from_slice(&content)
+        .map_err(|e| format!("failed to parse plan '{}': {}", path.display(), e))?;
+
+    Ok(plan
+        .secrets
+        .into_iter()
+        .map(|planned| (planned.secret, planned.digest))
+        .collect())
+}
+
+fn expected_digest(plan: Option<&Digests>, name: &str) -> Option<String> {
+    plan?.get(name).cloned().flatten()
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn verify(
+    manifest: &super::Manifest,
+    plan: Option<&Digests>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use super::sink::Sink;
+
+    let mut statuses = Vec::new();
+    for secret in manifest.secrets.iter() {
+        let mut status = Status {
+            secret: secret.name.clone(),
+            destination: secret
+                .sink
+                .describe_destination(&secret.name, secret.destination_path.as_deref()),
+            expected_digest: expected_digest(plan, &secret.name),
+            ..Default::default()
+        };
+        let ownership =
+            super::ownership::Ownership::resolve(&secret.owner, &secret.group, &secret.mode)
+                .map_err(|e| status.drift.push(e))
+                .ok();
+
+        match (&secret.sink, &secret.destination_path) {
+            (Sink::File, Some(destination)) if secret.is_tree() => {
+                let entries = verify_tree(secret, destination, ownership, plan, &mut status).await;
+                statuses.push(status);
+                statuses.extend(entries);
+                continue;
+            }
+            (Sink::File, Some(destination)) => {
+                status.digest = match inspect(destination, ownership, &mut status) {
+                    true => digest_file(destination, &mut status.drift).await,
+                    false => None,
+                };
+            }
+            (Sink::Credential(sink), _) => {
+                let destination = super::credential::directory(sink)
+                    .join(sink.name.as_deref().unwrap_or(&secret.name));
+                let ownership = Some(super::credential::CREDENTIAL_OWNERSHIP);
+                if inspect(&destination, ownership, &mut status) && !sink.encrypted {
+                    status.digest = digest_file(&destination, &mut status.drift).await;
+                }
+            }
+            (Sink::EnvFile(sink), _) => {
+                verify_env_file(sink, &secret.name, ownership, &mut status);
+            }
+            // Keys and commands leave nothing to compare
+            _ => {}
+        }
+
+        compare_digest(&mut status);
+        statuses.push(status);
+    }
+
+    let drifted = statuses
+        .iter()
+        .filter(|status| !status.drift.is_empty())
+        .count();
+    let unverifiable = statuses.iter().filter(|status| status.unverifiable).count();
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&Report { secrets: statuses })?
+    );
+    if unverifiable > 0 {
+        log::warn!(count = unverifiable; "Content of secrets in the plan can't be verified");
+    }
+    if drifted > 0 {
+        return Err(format!("found drift in {} secret(s)", drifted).into());
+    }
+    Ok(())
+}
+
+// Directory entries are reported one by one, after the directory itself. Entries of the plan that
+// aren't delivered are drift of the directory, delivered entries missing from the plan are drift
+// of their own.
+async fn verify_tree(
+    secret: &super::Secret,
+    destination: &Path,
+    ownership: Option<super::ownership::Ownership>,
+    plan: Option<&Digests>,
+    status: &mut Status,
+) -> Vec<Status> {
+    if !destination.is_dir() {
+        status.exists = Some(false);
+        status.drift.push("directory is missing".to_string());
+        return Vec::new();
+    }
+    status.exists = Some(true);
+
+    let mut files = Vec::new();
+    if let Err(e) = super::source::walk_directory(destination, &mut files) {
+        status.drift.push(format!("failed to walk: {}", e));
+    }
+    files.sort();
+
+    let mut entries = Vec::new();
+    for path in files {
+        let relative = path.strip_prefix(destination).unwrap_or(&path);
+        let name = format!("{}/{}", secret.name, relative.display());
+        let mut entry = Status {
+            destination: path.display().to_string(),
+            expected_digest: expected_digest(plan, &name),
+            ..Default::default()
+        };
+        if plan.is_some_and(|plan| !plan.contains_key(&name)) {
+            entry.drift.push("entry is not in the plan".to_string());
+        }
+        entry.secret = name;
+        if inspect(&path, ownership, &mut entry) {
+            entry.digest = digest_file(&path, &mut entry.drift).await;
+        }
+        compare_digest(&mut entry);
+        entries.push(entry);
+    }
+
+    let prefix = format!("{}/", secret.name);
+    let planned = plan.into_iter().flat_map(|plan| plan.keys());
+    for name in planned.filter(|name| name.starts_with(&prefix)) {
+        if !entries.iter().any(|entry| entry.secret == *name) {
+            status
+                .drift
+                .push(format!("entry '{}' is missing", &name[prefix.len()..]));
+        }
+    }
+
+    entries
+}
+
+// The file is shared by secrets, each compares its own variable.
+fn verify_env_file(
+    sink: &super::sink::EnvFileSink,
+    secret_name: &str,
+    ownership: Option<super::ownership::Ownership>,
+    status: &mut Status,
+) {
+    if !inspect(&sink.path, ownership, status) {
+        return;
+    }
+
+    let key = sink.key(secret_name);
+    match super::env_file::read_value(&sink.path, &key) {
+        Ok(Some(value)) => {
+            status.digest = Some(digest_value(&value, status.expected_digest.as_deref()));
+        }
+        Ok(None) => status.drift.push(format!("variable '{}' is missing", key)),
+        Err(e) => status.drift.push(format!("failed to read: {}", e)),
+    }
+}
+
+// Reports existence, ownership and mode of the file, true when it's a file whose content can be
+// compared.
+fn inspect(
+    path: &Path,
+    ownership: Option<super::ownership::Ownership>,
+    status: &mut Status,
+) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            status.exists = Some(false);
+            status.drift.push("destination is missing".to_string());
+            return false;
+        }
+        Err(e) => {
+            status.drift.push(format!("failed to inspect: {}", e));
+            return false;
+        }
+    };
+    status.exists = Some(true);
+    status.uid = Some(metadata.uid());
+    status.gid = Some(metadata.gid());
+    let mode = metadata.mode() & 0o7777;
+    status.mode = Some(format!("{:04o}", mode));
+
+    if let Some(ownership) = ownership {
+        if metadata.uid() != ownership.uid {
+            status.drift.push(format!(
+                "owner is {}, expected {}",
+                metadata.uid(),
+                ownership.uid
+            ));
+        }
+        if metadata.gid() != ownership.gid {
+            status.drift.push(format!(
+                "group is {}, expected {}",
+                metadata.gid(),
+                ownership.gid
+            ));
+        }
+        if mode != ownership.mode {
+            status.drift.push(format!(
+                "mode is {:04o}, expected {:04o}",
+                mode, ownership.mode
+            ));
+        }
+    }
+    if !metadata.is_file() {
+        status.drift.push("destination is not a file".to_string());
+        return false;
+    }
+    true
+}
+
+async fn digest_file(path: &Path, drift: &mut Vec<String>) -> Option<String> {
+    let source = super::source::Source::File {
+        path: path.to_owned(),
+    };
+    let content = async {
+        let mut payload = source.open().await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(payload.content().await?)
+    };
+    match content.await {
+        Ok(content) => Some(content.digest()),
+        Err(e) => {
+            drift.push(format!("failed to read: {}", e));
+            None
+        }
+    }
+}
+
+// NOTE; The receiver strips one trailing newline off values of environment files, the sender
+// digested the value with or without it
+fn digest_value(value: &str, expected: Option<&str>) -> String {
+    let mut content = super::audit::Content::default();
+    content.update(value.as_bytes());
+    let digest = content.digest();
+    content.update(b"\n");
+    match expected {
+        Some(expected) if expected == content.digest() => content.digest(),
+        _ => digest,
+    }
+}
+
+fn compare_digest(status: &mut Status) {
+    match (&status.digest, &status.expected_digest) {
+        (Some(digest), Some(expected)) if digest != expected => {
+            status.drift.push("content differs".to_string());
+        }
+        (None, Some(_)) => status.unverifiable = true,
+        _ => {}
+    }
+}
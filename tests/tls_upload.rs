@@ -0,0 +1,86 @@
+// Uploads a secret over mutual TLS to a receiver started as root, whose network worker is killed on
+// syscalls outside its allowlist. Catches syscalls of the TLS and HTTP stacks missing from the
+// allowlist, eg; after dependency updates.
+//
+// NOTE; The certificate and key in tests/fixtures are self-signed for this test only
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const PIN: &str = "09e441a5052bd24eb0801cee9d9ba4e60d1316793eb84efec920f84abfa871c7";
+
+fn tls_arguments(directory: &Path) -> Vec<String> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    vec![
+        "--unix-socket".into(),
+        directory.join("receive.sock").display().to_string(),
+        "--tls-cert".into(),
+        fixtures.join("tls.crt").display().to_string(),
+        "--tls-key".into(),
+        fixtures.join("tls.key").display().to_string(),
+        "--tls-pin".into(),
+        PIN.into(),
+        "--timeout".into(),
+        "30".into(),
+    ]
+}
+
+#[test]
+fn tls_upload_to_filtered_worker() {
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("Skipped, privilege separation and the syscall filter require root");
+        return;
+    }
+
+    let directory = std::env::temp_dir().join(format!("bss-tls-{}", std::process::id()));
+    std::fs::create_dir_all(directory.join("out")).unwrap();
+    let destination = directory.join("out/secret");
+    let manifest = directory.join("manifest.toml");
+    std::fs::write(
+        &manifest,
+        format!(
+            r#"
+[[secrets]]
+name = "secret"
+source = {{ type = "literal", value = "content" }}
+destination_path = "{}"
+owner = "root"
+group = "root"
+mode = "0400"
+"#,
+            destination.display()
+        ),
+    )
+    .unwrap();
+
+    let binary = env!("CARGO_BIN_EXE_bss");
+    let mut receiver = Command::new(binary)
+        .args(tls_arguments(&directory))
+        .arg("receive")
+        .arg(&manifest)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    while !directory.join("receive.sock").exists() && started.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let sender = Command::new(binary)
+        .args(tls_arguments(&directory))
+        .arg("send")
+        .arg(&manifest)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    if !sender.success() {
+        let _ = receiver.kill();
+    }
+    let receiver = receiver.wait().unwrap();
+    let content = std::fs::read(&destination);
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert!(sender.success(), "sender failed with {}", sender);
+    assert!(receiver.success(), "receiver failed with {}", receiver);
+    assert_eq!(content.unwrap(), b"content");
+}